@@ -0,0 +1,33 @@
+// An example of an asynchronous request handler. While a request waits for a
+// slow computation on another thread, the same context keeps handling other
+// requests.
+
+use std::time::Duration;
+
+use unit_rs::{spawn_blocking, AsyncRequest, Unit, UnitError};
+
+fn main() {
+    let mut unit = Unit::new().unwrap();
+
+    unit.set_async_request_handler(|req: AsyncRequest| async move {
+        let slow = req
+            .request()
+            .map(|req| req.path() == "/slow")
+            .unwrap_or(false);
+
+        let body = if slow {
+            spawn_blocking(|| {
+                std::thread::sleep(Duration::from_secs(2));
+                "Slow response!\n"
+            })
+            .await
+        } else {
+            "Fast response!\n"
+        };
+
+        let req = req.request().ok_or_else(UnitError::error)?;
+        req.send_response(200, &[("Content-Type", "text/plain")], body)
+    });
+
+    unit.run();
+}
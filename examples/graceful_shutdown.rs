@@ -0,0 +1,60 @@
+// An example that lets in-flight requests finish when Unit stops or restarts
+// the application, and then cleans up per-thread and process-wide state.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use unit_rs::{Request, Unit};
+
+fn main() {
+    // Global state, which must be flushed once all threads are done with it.
+    let total_requests = Arc::new(AtomicU64::new(0));
+
+    let process_total = total_requests.clone();
+    let mut unit = Unit::builder()
+        .drain_period(Duration::from_secs(5))
+        .on_quit(|| eprintln!("Main thread stopped"))
+        .on_process_quit(move || {
+            eprintln!("Handled {} requests", process_total.load(Ordering::Acquire));
+        })
+        .build()
+        .unwrap();
+
+    let thread_total = total_requests.clone();
+    let thread = std::thread::spawn(move || {
+        let mut unit = Unit::builder()
+            .drain_period(Duration::from_secs(5))
+            .on_quit(|| eprintln!("Worker thread stopped"))
+            .build()
+            .unwrap();
+
+        unit.set_request_handler(move |req: Request| handle(req, &thread_total));
+
+        unit.run();
+    });
+
+    unit.set_request_handler(move |req: Request| handle(req, &total_requests));
+
+    unit.run();
+
+    thread.join().unwrap();
+}
+
+fn handle(req: Request, total_requests: &AtomicU64) -> unit_rs::UnitResult<()> {
+    total_requests.fetch_add(1, Ordering::Release);
+
+    // Finish the request later; the drain period gives it time to complete
+    // even if Unit asks the application to quit in the meantime.
+    let completer = req.detach().into_completer();
+
+    std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_secs(1));
+
+        completer
+            .complete(|req| req.send_response(200, &[("Content-Type", "text/plain")], "Done.\n"))
+            .ok();
+    });
+
+    Ok(())
+}
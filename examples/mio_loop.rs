@@ -0,0 +1,50 @@
+// An example that embeds Unit in a mio event loop, which also runs its own
+// periodic work.
+
+use std::time::{Duration, Instant};
+
+use mio::{Events, Interest, Poll, Token};
+use unit_rs::{Request, Unit};
+
+const UNIT: Token = Token(0);
+
+fn main() {
+    let mut unit = Unit::new().unwrap();
+
+    unit.set_request_handler(|req: Request| {
+        let headers = &[("Content-Type", "text/plain")];
+        req.send_response(200, headers, "Hello from a mio loop!\n")
+    });
+
+    let mut poll = Poll::new().unwrap();
+    let mut events = Events::with_capacity(16);
+
+    poll.registry()
+        .register(&mut unit, UNIT, Interest::READABLE)
+        .unwrap();
+
+    let mut last_tick = Instant::now();
+
+    loop {
+        poll.poll(&mut events, Some(Duration::from_secs(1)))
+            .unwrap();
+
+        if events.iter().any(|event| event.token() == UNIT) {
+            if !unit.run_once(Some(Duration::ZERO)) {
+                break;
+            }
+
+            // Unit may have added or removed ports.
+            poll.registry()
+                .reregister(&mut unit, UNIT, Interest::READABLE)
+                .unwrap();
+        }
+
+        if last_tick.elapsed() >= Duration::from_secs(10) {
+            eprintln!("Still running");
+            last_tick = Instant::now();
+        }
+    }
+
+    poll.registry().deregister(&mut unit).unwrap();
+}
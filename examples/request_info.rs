@@ -28,9 +28,15 @@ fn request_handler(req: Request) -> UnitResult<()> {
         write!(w, "  Remote addr: {}\n", req.remote())?;
         write!(w, "  Local addr: {}\n", req.local())?;
         write!(w, "  Server name: {}\n", req.server_name())?;
+        write!(w, "  Scheme: {}\n", req.scheme())?;
+        write!(w, "  Host: {}\n", req.host())?;
+        write!(w, "  Port: {:?}\n", req.port())?;
         write!(w, "  Target: {}\n", req.target())?;
         write!(w, "  Path: {}\n", req.path())?;
         write!(w, "  Query: {}\n", req.query())?;
+        write!(w, "  Content length: {:?}\n", req.content_length())?;
+        write!(w, "  Content type: {:?}\n", req.content_type())?;
+        write!(w, "  App target: {}\n", req.app_target())?;
         write!(w, "  Fields:\n")?;
         for (name, value) in req.fields() {
             write!(w, "    {}: {}\n", name, value).unwrap();
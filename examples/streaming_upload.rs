@@ -0,0 +1,40 @@
+// An example that writes request bodies to disk as they arrive, instead of
+// letting Unit buffer the whole upload first.
+
+use std::io::Write;
+
+use unit_rs::{AsyncHandler, AsyncRequest, Unit, UnitError};
+
+fn main() {
+    let mut unit = Unit::new().unwrap();
+
+    unit.set_request_handler(
+        AsyncHandler::new(|req: AsyncRequest| async move {
+            let mut file =
+                std::fs::File::create("/tmp/upload.bin").map_err(|_| UnitError::error())?;
+
+            let mut body = req.body();
+            let mut buf = vec![0u8; 64 * 1024];
+            let mut total = 0;
+
+            loop {
+                let bytes = body.read(&mut buf).await.map_err(|_| UnitError::error())?;
+
+                if bytes == 0 {
+                    break;
+                }
+
+                file.write_all(&buf[..bytes])
+                    .map_err(|_| UnitError::error())?;
+                total += bytes;
+            }
+
+            let req = req.request().ok_or_else(UnitError::error)?;
+            let response = format!("Saved {} bytes.\n", total);
+            req.send_response(200, &[("Content-Type", "text/plain")], response)
+        })
+        .stream_body(),
+    );
+
+    unit.run();
+}
@@ -0,0 +1,25 @@
+// An example that handles requests on a pool of threads, one per CPU, each
+// with its own handler state.
+
+use unit_rs::{Request, Unit, UnitResult, UnitService};
+
+struct CounterService {
+    visits: u64,
+}
+
+impl UnitService for CounterService {
+    fn handle_request(&mut self, req: Request) -> UnitResult<()> {
+        self.visits += 1;
+
+        let headers = &[("Content-Type", "text/plain")];
+        let body = format!("Visits on this thread: {}\n", self.visits);
+        req.send_response(200, headers, body)
+    }
+}
+
+fn main() {
+    let mut unit = Unit::new().unwrap();
+
+    unit.run_threads(None, || CounterService { visits: 0 })
+        .unwrap();
+}
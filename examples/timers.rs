@@ -0,0 +1,38 @@
+// An example that periodically updates state owned by the request handler's
+// thread, without locks.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use unit_rs::{Request, Unit};
+
+fn main() {
+    let mut unit = Unit::new().unwrap();
+
+    // Not Send nor Sync; only used on this context's thread.
+    let last_refresh = Rc::new(RefCell::new(Instant::now()));
+    let refreshes = Rc::new(RefCell::new(0u64));
+
+    let timer_refresh = last_refresh.clone();
+    let timer_refreshes = refreshes.clone();
+    unit.schedule_interval(Duration::from_secs(1), move || {
+        *timer_refresh.borrow_mut() = Instant::now();
+        *timer_refreshes.borrow_mut() += 1;
+    });
+
+    unit.schedule_once(Duration::from_secs(5), || {
+        eprintln!("Application running for 5 seconds");
+    });
+
+    unit.set_request_handler(move |req: Request| {
+        let body = format!(
+            "Refreshed {} times, last time {:?} ago\n",
+            refreshes.borrow(),
+            last_refresh.borrow().elapsed(),
+        );
+        req.send_response(200, &[("Content-Type", "text/plain")], body)
+    });
+
+    unit.run();
+}
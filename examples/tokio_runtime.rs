@@ -0,0 +1,19 @@
+// An example that handles requests as part of a tokio runtime, instead of
+// blocking the thread in Unit's own event loop.
+
+use unit_rs::{Request, Unit};
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let mut unit = Unit::new().unwrap();
+
+    unit.set_request_handler(|req: Request| {
+        let headers = &[("Content-Type", "text/plain")];
+        let body = "Hello from tokio!\n";
+        req.send_response(200, headers, body)?;
+
+        Ok(())
+    });
+
+    unit.run_async().await.unwrap();
+}
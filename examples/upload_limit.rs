@@ -0,0 +1,39 @@
+// An example that rejects uploads that are too large as soon as the request
+// headers arrive, before Unit buffers the whole request body.
+
+use unit_rs::{HeadersAction, Request, Unit, UnitResult, UnitService};
+
+const MAX_UPLOAD_SIZE: u64 = 1024 * 1024;
+
+struct UploadService;
+
+impl UnitService for UploadService {
+    fn on_headers(&mut self, req: &Request) -> UnitResult<HeadersAction> {
+        let content_length = req.content_length().unwrap_or(0);
+
+        if content_length > MAX_UPLOAD_SIZE {
+            let headers = &[("Content-Type", "text/plain")];
+            req.send_response(413, headers, "Upload too large.\n")?;
+
+            return Ok(HeadersAction::Finish);
+        }
+
+        Ok(HeadersAction::Buffer)
+    }
+
+    fn handle_request(&mut self, req: Request) -> UnitResult<()> {
+        let body = req.body().read_to_vec().unwrap_or_default();
+
+        let headers = &[("Content-Type", "text/plain")];
+        let response = format!("Received {} bytes.\n", body.len());
+        req.send_response(200, headers, response)
+    }
+}
+
+fn main() {
+    let mut unit = Unit::new().unwrap();
+
+    unit.set_request_handler(UploadService);
+
+    unit.run();
+}
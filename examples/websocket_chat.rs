@@ -0,0 +1,44 @@
+// An example of a chat server, where messages from every WebSocket connection
+// are broadcast to all other connections, even if they are handled by other
+// threads.
+
+use unit_rs::websocket::{MessageHandler, WebSocket, WebSocketHub, WebSocketMessage};
+use unit_rs::{Request, Unit};
+
+fn main() {
+    let hub = WebSocketHub::new();
+    let mut threads = Vec::new();
+
+    for _ in 0..4 {
+        let hub = hub.clone();
+        threads.push(std::thread::spawn(move || worker(hub)));
+    }
+
+    for handle in threads {
+        handle.join().unwrap();
+    }
+}
+
+fn worker(hub: WebSocketHub) {
+    let mut unit = Unit::new().unwrap();
+
+    unit.set_request_handler(move |req: Request| {
+        let room_hub = hub.clone();
+
+        let ws = req.upgrade_websocket(MessageHandler::new(
+            move |ws: &WebSocket, message: WebSocketMessage| {
+                if let WebSocketMessage::Text(text) = message {
+                    room_hub.send_to_room_except("chat", text, ws);
+                }
+                Ok(())
+            },
+        ))?;
+
+        hub.join("chat", &ws)?;
+        hub.send_to_room("chat", format!("{} users online", hub.room_len("chat")));
+
+        Ok(())
+    });
+
+    unit.run();
+}
@@ -0,0 +1,26 @@
+// An example that upgrades requests to WebSocket connections, and echoes back
+// every frame received from the client.
+
+use unit_rs::websocket::{WebSocket, WebSocketFrame};
+use unit_rs::{Request, Unit};
+
+fn main() {
+    let mut unit = Unit::new().unwrap();
+
+    unit.set_request_handler(|req: Request| {
+        if req.path() != "/ws" {
+            let headers = &[("Content-Type", "text/plain")];
+            req.send_response(200, headers, "Connect to /ws with a WebSocket client.\n")?;
+            return Ok(());
+        }
+
+        req.upgrade_websocket(|ws: &WebSocket, mut frame: WebSocketFrame| {
+            let payload = frame.read_to_vec().unwrap();
+            ws.send(frame.opcode(), frame.is_final(), payload)
+        })?;
+
+        Ok(())
+    });
+
+    unit.run();
+}
@@ -0,0 +1,193 @@
+use std::time::Duration;
+
+use crate::error::UnitBuildError;
+use crate::panic::PanicPolicy;
+use crate::unit::Unit;
+
+/// A builder for [`Unit`] contexts, with options for tuning `libunit`.
+///
+/// The `libunit` options (such as [`shm_limit()`](UnitBuilder::shm_limit))
+/// apply to the whole process, and can only be set when building the first
+/// [`Unit`] context. The callbacks apply to the context being built.
+///
+/// # Example
+///
+/// ```no_run
+/// use unit_rs::{Request, Unit};
+///
+/// fn main() {
+///     let mut unit = Unit::builder()
+///         .shm_limit(64 * 1024 * 1024)
+///         .request_limit(10_000)
+///         .on_ready(|| eprintln!("Ready to receive requests"))
+///         .on_quit(|| eprintln!("Quitting"))
+///         .build()
+///         .unwrap();
+///
+///     unit.set_request_handler(|req: Request| {
+///         req.send_response(200, &[("Content-Type", "text/plain")], "Hello!\n")
+///     });
+///
+///     unit.run();
+/// }
+/// ```
+#[derive(Default)]
+pub struct UnitBuilder {
+    pub(crate) max_pending_requests: Option<u32>,
+    pub(crate) request_data_size: Option<u32>,
+    pub(crate) shm_limit: Option<u32>,
+    pub(crate) request_limit: Option<u32>,
+    pub(crate) on_ready: Option<Box<dyn FnOnce()>>,
+    pub(crate) on_quit: Option<Box<dyn FnOnce()>>,
+    pub(crate) on_process_quit: Option<Box<dyn FnOnce() + Send>>,
+    pub(crate) drain_period: Option<Duration>,
+    pub(crate) panic_policy: PanicPolicy,
+}
+
+impl UnitBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the maximum number of requests that may be read from the shared
+    /// queue but not yet be handled, per context.
+    ///
+    /// Must be between 1 and `i32::MAX`.
+    pub fn max_pending_requests(mut self, max_pending_requests: u32) -> Self {
+        self.max_pending_requests = Some(max_pending_requests);
+        self
+    }
+
+    /// Set the size of the user data area that `libunit` allocates for each
+    /// request.
+    ///
+    /// This library keeps its own per-request state, so this is only useful
+    /// when sharing the process with other `libunit` users.
+    pub fn request_data_size(mut self, request_data_size: u32) -> Self {
+        self.request_data_size = Some(request_data_size);
+        self
+    }
+
+    /// Set the maximum amount of shared memory, in bytes, that the process may
+    /// use for sending responses to the Unit server.
+    ///
+    /// Must be greater than 0; by default, there is no limit.
+    pub fn shm_limit(mut self, shm_limit: u32) -> Self {
+        self.shm_limit = Some(shm_limit);
+        self
+    }
+
+    /// Set the number of requests after which the process stops receiving
+    /// new requests, and quits once the current requests are finished. The
+    /// Unit server will start a new process in its place.
+    ///
+    /// Must be greater than 0; by default, there is no limit.
+    pub fn request_limit(mut self, request_limit: u32) -> Self {
+        self.request_limit = Some(request_limit);
+        self
+    }
+
+    /// Set a function to be called once the Unit server is ready to send
+    /// requests to this process.
+    ///
+    /// This is only called for the first [`Unit`] context, before
+    /// [`build()`](UnitBuilder::build) returns.
+    pub fn on_ready(mut self, f: impl FnOnce() + 'static) -> Self {
+        self.on_ready = Some(Box::new(f));
+        self
+    }
+
+    /// Set a function to be called on this context's thread when its
+    /// [`Unit::run()`] loop ends, before it returns.
+    ///
+    /// The loop ends when the Unit server asks the application to quit, when
+    /// a [`UnitQuitHandle`](crate::UnitQuitHandle) is used, or when a handler
+    /// panics with the default [`PanicPolicy`](crate::PanicPolicy), in which
+    /// case this is called before the panic is resumed.
+    ///
+    /// If a [drain period](UnitBuilder::drain_period) is set, this is called
+    /// after it ends.
+    pub fn on_quit(mut self, f: impl FnOnce() + 'static) -> Self {
+        self.on_quit = Some(Box::new(f));
+        self
+    }
+
+    /// Set a function to be called once per process, when no contexts are
+    /// left to handle requests.
+    ///
+    /// Contexts are counted from their creation until their [`Unit::run()`]
+    /// loop ends, for any of the reasons listed in
+    /// [`on_quit()`](UnitBuilder::on_quit), or until they are dropped. This is
+    /// called by the last of them, after its own `on_quit()` callback, which
+    /// makes it a good place to close resources shared by all threads, such as
+    /// database pools.
+    pub fn on_process_quit(mut self, f: impl FnOnce() + Send + 'static) -> Self {
+        self.on_process_quit = Some(Box::new(f));
+        self
+    }
+
+    /// Keep handling in-flight requests for up to this long after the Unit
+    /// server asks the application to quit.
+    ///
+    /// During this period, the context's event loop keeps running until all
+    /// of its detached requests, request bodies and asynchronous tasks are
+    /// finished. By default, the event loop stops immediately.
+    pub fn drain_period(mut self, drain_period: Duration) -> Self {
+        self.drain_period = Some(drain_period);
+        self
+    }
+
+    /// Set what this context does when one of its request handlers panics.
+    ///
+    /// See [`Unit::set_panic_policy()`].
+    pub fn panic_policy(mut self, panic_policy: PanicPolicy) -> Self {
+        self.panic_policy = panic_policy;
+        self
+    }
+
+    pub(crate) fn has_init_options(&self) -> bool {
+        self.max_pending_requests.is_some()
+            || self.request_data_size.is_some()
+            || self.shm_limit.is_some()
+            || self.request_limit.is_some()
+            || self.on_ready.is_some()
+            || self.on_process_quit.is_some()
+    }
+
+    fn validate(&self) -> Result<(), UnitBuildError> {
+        if let Some(max_pending_requests) = self.max_pending_requests {
+            if max_pending_requests == 0 || max_pending_requests > i32::MAX as u32 {
+                return Err(UnitBuildError::InvalidOption {
+                    name: "max_pending_requests",
+                    reason: "must be between 1 and i32::MAX",
+                });
+            }
+        }
+
+        if self.shm_limit == Some(0) {
+            return Err(UnitBuildError::InvalidOption {
+                name: "shm_limit",
+                reason: "must be greater than 0",
+            });
+        }
+
+        if self.request_limit == Some(0) {
+            return Err(UnitBuildError::InvalidOption {
+                name: "request_limit",
+                reason: "must be greater than 0",
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Validate the options, and create a new Unit context.
+    ///
+    /// See [`Unit::new()`] for how multiple contexts are linked together.
+    /// Returns an error if the `libunit` options are not valid, or if they
+    /// were set after the first context was already created.
+    pub fn build(self) -> Result<Unit, UnitBuildError> {
+        self.validate()?;
+        Unit::with_builder(self)
+    }
+}
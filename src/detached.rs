@@ -0,0 +1,302 @@
+use std::cell::Cell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::error::{UnitError, UnitResult};
+use crate::event_loop::ContextMailbox;
+use crate::nxt_unit::{self, nxt_unit_request_info_t};
+use crate::request::Request;
+use crate::unit::ContextData;
+
+static NEXT_COMPLETER_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A request that outlives the request handler that received it.
+///
+/// This object is created with [`Request::detach()`], and can be used to send a
+/// response at a later time, from the thread of the [`Unit`](crate::Unit)
+/// context that received the request. It is not [`Send`] nor [`Sync`]; in
+/// order to finish the request from another thread, convert it into a
+/// [`RequestCompleter`] with [`DetachedRequest::into_completer()`].
+///
+/// The request is finished when this object is dropped. If no response was
+/// sent by then, Unit will respond with an error.
+///
+/// If the client disconnects or the Unit server quits in the meantime, the
+/// request will be closed, and all further attempts to access it will fail.
+/// Writers for the response that are still in use will return errors with
+/// the [`ConnectionAborted`](std::io::ErrorKind::ConnectionAborted) kind.
+pub struct DetachedRequest {
+    nxt_request: *mut nxt_unit_request_info_t,
+    state: Rc<DetachedState>,
+}
+
+/// The state of a detached request, shared with its context.
+#[derive(Default)]
+pub(crate) struct DetachedState {
+    // Set when the client disconnected, or the Unit server is quitting.
+    closed: Cell<bool>,
+    // Set when the request was finished, and may no longer be accessed.
+    finished: Cell<bool>,
+}
+
+impl DetachedState {
+    pub(crate) fn mark_closed(&self) {
+        self.closed.set(true);
+    }
+
+    pub(crate) fn mark_finished(&self) {
+        self.closed.set(true);
+        self.finished.set(true);
+    }
+}
+
+impl DetachedRequest {
+    /// Detach a request from its request handler.
+    ///
+    /// # Safety
+    /// Must be called on the context's thread, while the request handler runs.
+    pub(crate) unsafe fn new(nxt_request: *mut nxt_unit_request_info_t) -> Self {
+        let context_data = (*(*nxt_request).ctx).data as *mut ContextData;
+
+        // A request that is already detached may be detached again through
+        // `DetachedRequest::request()`; both handles share the same state, and
+        // only the first one to finish will finish the request.
+        let context_data = &mut *context_data;
+
+        let state = match context_data.detached_requests.get(&nxt_request) {
+            Some(state) => state.clone(),
+            None => {
+                let state = Rc::new(DetachedState::default());
+                context_data
+                    .detached_requests
+                    .insert(nxt_request, state.clone());
+                context_data.request_detached = true;
+                state
+            }
+        };
+
+        DetachedRequest { nxt_request, state }
+    }
+
+    /// Access the request, in order to inspect it or send (parts of) a
+    /// response.
+    ///
+    /// Returns `None` if the request was already closed.
+    pub fn request(&self) -> Option<Request<'_>> {
+        if self.is_closed() {
+            return None;
+        }
+
+        Some(Request {
+            nxt_request: self.nxt_request,
+            _lifetime: Default::default(),
+        })
+    }
+
+    /// Return whether or not the request was closed, either because the
+    /// client disconnected or because the Unit server is quitting.
+    pub fn is_closed(&self) -> bool {
+        self.state.closed.get()
+    }
+
+    /// Finish the request with the given result.
+    ///
+    /// If the result is an error and no response was sent, Unit will respond
+    /// with an error.
+    pub fn finish(self, result: UnitResult<()>) {
+        self.finish_ref(result);
+    }
+
+    /// Call a function with the request, and finish the request with its
+    /// result, similar to a request handler.
+    ///
+    /// If the request was already closed, the function is not called.
+    pub fn finish_with(self, f: impl FnOnce(Request) -> UnitResult<()>) {
+        let result = match self.request() {
+            Some(request) => f(request),
+            None => return,
+        };
+        self.finish(result);
+    }
+
+    /// Convert this request into a handle that can be sent to other threads,
+    /// and used to finish the request from there.
+    ///
+    /// The request itself is kept by its Unit context until the handle is
+    /// used or dropped.
+    pub fn into_completer(self) -> RequestCompleter {
+        let id = NEXT_COMPLETER_ID.fetch_add(1, Ordering::Relaxed);
+
+        // SAFETY: Detached requests are not Send, so this runs on the thread
+        // of the context that owns the request. The context is still alive if
+        // the request was not finished.
+        let mailbox = unsafe {
+            let context_data = self.context_data();
+
+            if context_data.is_null() {
+                None
+            } else {
+                let mailbox = (*context_data).mailbox.clone();
+                (*context_data).parked_requests.insert(id, self);
+                Some(mailbox)
+            }
+        };
+
+        RequestCompleter { id, mailbox }
+    }
+
+    unsafe fn context_data(&self) -> *mut ContextData {
+        if self.state.finished.get() {
+            return std::ptr::null_mut();
+        }
+        (*(*self.nxt_request).ctx).data as *mut ContextData
+    }
+
+    /// Finish the request with the given result, unless it was already
+    /// finished or closed.
+    pub(crate) fn finish_ref(&self, result: UnitResult<()>) {
+        let rc = match result {
+            Ok(()) => nxt_unit::NXT_UNIT_OK as i32,
+            Err(UnitError(rc)) => rc,
+        };
+        self.done(rc);
+    }
+
+    fn done(&self, rc: i32) {
+        if self.state.finished.get() {
+            return;
+        }
+
+        // SAFETY: The request was not finished, so it is still owned by this
+        // object, and its context is still alive.
+        unsafe {
+            let context_data = self.context_data();
+
+            if !context_data.is_null() {
+                (*context_data).detached_requests.remove(&self.nxt_request);
+                (*context_data).pending_bodies.remove(&self.nxt_request);
+                (*context_data).body_wakers.remove(&self.nxt_request);
+            }
+
+            self.state.mark_finished();
+            nxt_unit::nxt_unit_request_done(self.nxt_request, rc);
+        }
+    }
+}
+
+impl Drop for DetachedRequest {
+    fn drop(&mut self) {
+        self.done(nxt_unit::NXT_UNIT_ERROR as i32);
+    }
+}
+
+/// A handle to a [`DetachedRequest`] that can be sent to other threads.
+///
+/// The handle can be used to finish the request from any thread; the actual
+/// work will be executed on the thread of the [`Unit`](crate::Unit) context
+/// that owns the request, during its [`Unit::run()`](crate::Unit::run) loop.
+///
+/// If this handle is dropped without being used, the request will be finished
+/// with an error.
+///
+/// # Example
+///
+/// ```no_run
+/// use unit_rs::{Request, Unit};
+///
+/// fn main() {
+///     let mut unit = Unit::new().unwrap();
+///
+///     unit.set_request_handler(|req: Request| {
+///         let completer = req.detach().into_completer();
+///
+///         std::thread::spawn(move || {
+///             let body = format!("Computed in the background: {}\n", 6 * 7);
+///
+///             completer
+///                 .complete(move |req| {
+///                     req.send_response(200, &[("Content-Type", "text/plain")], body)
+///                 })
+///                 .ok();
+///         });
+///
+///         Ok(())
+///     });
+///
+///     unit.run();
+/// }
+/// ```
+pub struct RequestCompleter {
+    id: u64,
+    mailbox: Option<Arc<ContextMailbox>>,
+}
+
+impl RequestCompleter {
+    /// Finish the request by calling the given function with it, on the
+    /// thread of the context that owns the request.
+    ///
+    /// If the request was already closed, the function is not called.
+    ///
+    /// Returns an error if the context that owns the request no longer
+    /// exists.
+    pub fn complete(
+        mut self,
+        f: impl FnOnce(Request) -> UnitResult<()> + Send + 'static,
+    ) -> UnitResult<()> {
+        let id = self.id;
+        let mailbox = self.mailbox.take().ok_or_else(UnitError::error)?;
+
+        let posted = mailbox.post(Box::new(move |context_data| {
+            // SAFETY: Tasks are executed on the context's thread, by its event
+            // loop.
+            let detached_request = unsafe { (*context_data).parked_requests.remove(&id) };
+
+            if let Some(detached_request) = detached_request {
+                detached_request.finish_with(f);
+            }
+        }));
+
+        if posted {
+            Ok(())
+        } else {
+            Err(UnitError::error())
+        }
+    }
+}
+
+impl Drop for RequestCompleter {
+    fn drop(&mut self) {
+        let id = self.id;
+
+        if let Some(mailbox) = self.mailbox.take() {
+            mailbox.post(Box::new(move |context_data| {
+                // SAFETY: Tasks are executed on the context's thread, by its
+                // event loop. Dropping the request will finish it.
+                drop(unsafe { (*context_data).parked_requests.remove(&id) });
+            }));
+        }
+    }
+}
+
+/// Return whether or not the client of a request disconnected, or the Unit
+/// server is quitting.
+///
+/// Only detached requests can outlive their connection; requests that are
+/// being handled by a request handler are always open.
+///
+/// # Safety
+/// Must be called on the request context's thread, with a request that was
+/// not finished yet.
+pub(crate) unsafe fn request_is_closed(nxt_request: *mut nxt_unit_request_info_t) -> bool {
+    let context_data = (*(*nxt_request).ctx).data as *mut ContextData;
+
+    if context_data.is_null() {
+        return true;
+    }
+
+    match (*context_data).detached_requests.get(&nxt_request) {
+        Some(state) => state.closed.get(),
+        None => false,
+    }
+}
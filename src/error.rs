@@ -59,3 +59,41 @@ impl std::fmt::Display for UnitError {
         }
     }
 }
+
+/// Error returned when a [`Unit`](crate::Unit) could not be built with a
+/// [`UnitBuilder`](crate::UnitBuilder).
+#[derive(Debug, Clone, Copy)]
+pub enum UnitBuildError {
+    /// An option has an invalid value.
+    InvalidOption {
+        name: &'static str,
+        reason: &'static str,
+    },
+    /// Options that apply to the whole process were set after the first Unit
+    /// context was already created.
+    AlreadyInitialized,
+    /// Unit could not be initialized.
+    Init(UnitInitError),
+}
+
+impl From<UnitInitError> for UnitBuildError {
+    fn from(err: UnitInitError) -> Self {
+        UnitBuildError::Init(err)
+    }
+}
+
+impl std::error::Error for UnitBuildError {}
+
+impl std::fmt::Display for UnitBuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UnitBuildError::InvalidOption { name, reason } => {
+                write!(f, "Invalid Unit option `{}`: {}.", name, reason)
+            }
+            UnitBuildError::AlreadyInitialized => {
+                "Unit options can only be set for the first Unit context.".fmt(f)
+            }
+            UnitBuildError::Init(UnitInitError) => "Unit could not be initialized.".fmt(f),
+        }
+    }
+}
@@ -0,0 +1,430 @@
+// The event loop used by Unit contexts, in place of libunit's
+// `nxt_unit_run()`.
+//
+// `nxt_unit_run()` blocks in `recvmsg()` on the context's ports, and can only
+// be woken up by Unit itself. Contexts must also be woken up by other threads,
+// e.g. to post WebSocket frames or finish detached requests, and must wait for
+// timers, so the ports are made non-blocking and polled together with a
+// mailbox pipe. Each readable port is read with `nxt_unit_process_port_msg()`
+// until it returns `NXT_UNIT_AGAIN`, which is the normal result once a port
+// has no more messages.
+
+use std::os::unix::io::RawFd;
+use std::panic::AssertUnwindSafe;
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use libc::c_int;
+
+use crate::nxt_unit::{self, nxt_unit_ctx_t, nxt_unit_port_t, nxt_unit_t};
+use crate::panic;
+use crate::timer;
+use crate::unit::ContextData;
+
+// Equivalent of `NXT_UNIT_SHARED_PORT_ID`, which is a macro with a cast that
+// bindgen cannot translate.
+const SHARED_PORT_ID: u16 = 0xFFFF;
+
+// The port on which Unit delivers new requests to all contexts. It is
+// registered once, by the main context, and read from all contexts.
+static SHARED_PORT: AtomicPtr<nxt_unit_port_t> = AtomicPtr::new(null_mut());
+
+/// A task that must be executed on the thread of a specific Unit context.
+pub(crate) type ContextTask = Box<dyn FnOnce(*mut ContextData) + Send>;
+
+/// A queue of tasks for a Unit context, which can be filled from any thread.
+///
+/// Posting a task wakes up the context's event loop through a pipe.
+pub(crate) struct ContextMailbox {
+    tasks: Mutex<Vec<ContextTask>>,
+    read_fd: RawFd,
+    write_fd: RawFd,
+    closed: AtomicBool,
+}
+
+impl ContextMailbox {
+    pub(crate) fn new() -> std::io::Result<Self> {
+        let mut fds = [0; 2];
+
+        // SAFETY: The file descriptors are owned by this object, and closed
+        // when it is dropped.
+        unsafe {
+            if libc::pipe(fds.as_mut_ptr()) == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+
+            for fd in fds {
+                if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) == -1
+                    || set_nonblocking(fd).is_err()
+                {
+                    let err = std::io::Error::last_os_error();
+                    libc::close(fds[0]);
+                    libc::close(fds[1]);
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(ContextMailbox {
+            tasks: Mutex::new(Vec::new()),
+            read_fd: fds[0],
+            write_fd: fds[1],
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    /// Queue a task and wake up the context.
+    ///
+    /// Returns false if the context no longer exists, in which case the task
+    /// is dropped without being executed.
+    pub(crate) fn post(&self, task: ContextTask) -> bool {
+        let mut tasks = self.tasks.lock().expect("Mailbox should not be poisoned");
+
+        if self.closed.load(Ordering::Acquire) {
+            return false;
+        }
+
+        let was_empty = tasks.is_empty();
+        tasks.push(task);
+        drop(tasks);
+
+        if was_empty {
+            self.wake();
+        }
+
+        true
+    }
+
+    /// Wake up the context's event loop without queueing a task.
+    pub(crate) fn wake(&self) {
+        // SAFETY: Writing to a non-blocking pipe owned by this object. If the
+        // pipe is full, the context will wake up anyway.
+        unsafe {
+            libc::write(self.write_fd, [1u8].as_ptr() as *const libc::c_void, 1);
+        }
+    }
+
+    /// Mark the mailbox as closed, and return any tasks that were not yet
+    /// executed.
+    pub(crate) fn close(&self) -> Vec<ContextTask> {
+        let mut tasks = self.tasks.lock().expect("Mailbox should not be poisoned");
+        self.closed.store(true, Ordering::Release);
+        std::mem::take(&mut *tasks)
+    }
+
+    pub(crate) fn read_fd(&self) -> RawFd {
+        self.read_fd
+    }
+
+    fn take_tasks(&self) -> Vec<ContextTask> {
+        let mut buf = [0u8; 64];
+
+        // SAFETY: Reading from a non-blocking pipe owned by this object, into
+        // an initialized buffer.
+        unsafe {
+            while libc::read(
+                self.read_fd,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+            ) > 0
+            {}
+        }
+
+        let mut tasks = self.tasks.lock().expect("Mailbox should not be poisoned");
+        std::mem::take(&mut *tasks)
+    }
+}
+
+impl Drop for ContextMailbox {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read_fd);
+            libc::close(self.write_fd);
+        }
+    }
+}
+
+unsafe fn set_nonblocking(fd: RawFd) -> std::io::Result<()> {
+    let flags = libc::fcntl(fd, libc::F_GETFL);
+
+    if flags == -1 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+pub(crate) unsafe extern "C" fn add_port(
+    ctx: *mut nxt_unit_ctx_t,
+    port: *mut nxt_unit_port_t,
+) -> c_int {
+    // Ports without an incoming file descriptor are only used for sending.
+    if (*port).in_fd == -1 {
+        return nxt_unit::NXT_UNIT_OK as c_int;
+    }
+
+    // The event loop polls the ports by itself, and reads from them until
+    // they are drained.
+    if set_nonblocking((*port).in_fd).is_err() {
+        return nxt_unit::NXT_UNIT_ERROR as c_int;
+    }
+
+    if (*port).id.id == SHARED_PORT_ID {
+        SHARED_PORT.store(port, Ordering::Release);
+        return nxt_unit::NXT_UNIT_OK as c_int;
+    }
+
+    // SAFETY: Ports are added from the context's own thread, either during
+    // initialization or while processing its messages.
+    let context_data = (*ctx).data as *mut ContextData;
+
+    if !context_data.is_null() {
+        (*context_data).ports.push(port);
+    }
+
+    nxt_unit::NXT_UNIT_OK as c_int
+}
+
+pub(crate) unsafe extern "C" fn remove_port(
+    _unit: *mut nxt_unit_t,
+    ctx: *mut nxt_unit_ctx_t,
+    port: *mut nxt_unit_port_t,
+) {
+    let _ = SHARED_PORT.compare_exchange(port, null_mut(), Ordering::AcqRel, Ordering::Acquire);
+
+    if ctx.is_null() {
+        return;
+    }
+
+    let context_data = (*ctx).data as *mut ContextData;
+
+    if !context_data.is_null() {
+        (*context_data)
+            .ports
+            .retain(|registered| *registered != port);
+    }
+}
+
+pub(crate) unsafe extern "C" fn quit_handler(ctx: *mut nxt_unit_ctx_t) {
+    let context_data = (*ctx).data as *mut ContextData;
+
+    if !context_data.is_null() {
+        (*context_data).quit = true;
+    }
+}
+
+unsafe fn port_is_registered(context_data: *mut ContextData, port: *mut nxt_unit_port_t) -> bool {
+    SHARED_PORT.load(Ordering::Acquire) == port || (*context_data).ports.contains(&port)
+}
+
+/// Which of a context's event sources to wait for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EventSources {
+    /// The context's own ports and mailbox, and the shared port.
+    All,
+    /// The context's own ports and mailbox, which deliver responses, request
+    /// bodies and WebSocket frames for requests that were already received.
+    Context,
+    /// The shared port, which delivers new requests.
+    Shared,
+}
+
+/// Return the ports that the context should read from, including the shared
+/// port.
+///
+/// # Safety
+/// Must be called on the context's own thread, with valid context data.
+pub(crate) unsafe fn context_ports(context_data: *mut ContextData) -> Vec<*mut nxt_unit_port_t> {
+    selected_ports(context_data, EventSources::All)
+}
+
+unsafe fn selected_ports(
+    context_data: *mut ContextData,
+    sources: EventSources,
+) -> Vec<*mut nxt_unit_port_t> {
+    let mut ports = match sources {
+        EventSources::All | EventSources::Context => (*context_data).ports.clone(),
+        EventSources::Shared => Vec::new(),
+    };
+
+    let shared_port = SHARED_PORT.load(Ordering::Acquire);
+    if !shared_port.is_null() && sources != EventSources::Context {
+        ports.push(shared_port);
+    }
+
+    ports
+}
+
+/// Execute all tasks queued in the context's mailbox.
+///
+/// # Safety
+/// Must be called on the context's own thread, while no references to the
+/// context's data are alive.
+pub(crate) unsafe fn run_mailbox(context_data: *mut ContextData) {
+    let mailbox = (*context_data).mailbox.clone();
+
+    for task in mailbox.take_tasks() {
+        // Tasks may run user code, such as the functions given to a
+        // `RequestCompleter`.
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| task(context_data)));
+
+        if let Err(panic_payload) = result {
+            panic::handle_panic(context_data, "Context task", panic_payload);
+        }
+    }
+}
+
+/// Process messages from a port until it has no more messages.
+///
+/// # Safety
+/// Must be called on the context's own thread, while no references to the
+/// context's data are alive.
+pub(crate) unsafe fn process_port(ctx: *mut nxt_unit_ctx_t, port: *mut nxt_unit_port_t) {
+    let context_data = (*ctx).data as *mut ContextData;
+
+    // A previous message may have removed the port.
+    while !(*context_data).quit && port_is_registered(context_data, port) {
+        let rc = nxt_unit::nxt_unit_process_port_msg(ctx, port);
+
+        if rc == nxt_unit::NXT_UNIT_AGAIN as c_int {
+            break;
+        } else if rc != nxt_unit::NXT_UNIT_OK as c_int {
+            (*context_data).quit = true;
+            break;
+        }
+    }
+}
+
+/// Wait until either the context's ports or its mailbox have events, and
+/// process them.
+///
+/// Returns false when the context was asked to quit, or an unrecoverable error
+/// happened.
+///
+/// # Safety
+/// Must be called on the context's own thread, while no references to the
+/// context's data are alive.
+pub(crate) unsafe fn poll_context(ctx: *mut nxt_unit_ctx_t, timeout: Option<Duration>) -> bool {
+    poll_sources(ctx, timeout, EventSources::All)
+}
+
+/// Same as [`poll_context()`], but only waits for some of the context's event
+/// sources.
+///
+/// # Safety
+/// Same as [`poll_context()`].
+pub(crate) unsafe fn poll_sources(
+    ctx: *mut nxt_unit_ctx_t,
+    timeout: Option<Duration>,
+    sources: EventSources,
+) -> bool {
+    let context_data = (*ctx).data as *mut ContextData;
+
+    if (*context_data).quit {
+        return false;
+    }
+
+    let mailbox = (*context_data).mailbox.clone();
+    let ports = selected_ports(context_data, sources);
+
+    // The mailbox is not polled when only reading from the shared port; its
+    // entry is kept so that the ports' entries stay at the same offset.
+    let mut fds = Vec::with_capacity(ports.len() + 1);
+    fds.push(libc::pollfd {
+        fd: match sources {
+            EventSources::Shared => -1,
+            _ => mailbox.read_fd,
+        },
+        events: libc::POLLIN,
+        revents: 0,
+    });
+    for port in &ports {
+        fds.push(libc::pollfd {
+            fd: (**port).in_fd,
+            events: libc::POLLIN,
+            revents: 0,
+        });
+    }
+
+    // Wake up in time for the next timer; timers only run on the context's
+    // own loop, not when only reading from the shared port.
+    let timer_deadline = match sources {
+        EventSources::Shared => None,
+        _ => timer::next_deadline(context_data),
+    };
+    let timeout = match timer_deadline {
+        Some(deadline) => {
+            let until_deadline = deadline.saturating_duration_since(Instant::now());
+            Some(timeout.map_or(until_deadline, |timeout| timeout.min(until_deadline)))
+        }
+        None => timeout,
+    };
+
+    let timeout = match timeout {
+        // Round up, so that timers are not woken up a millisecond early.
+        Some(timeout) => {
+            let millis = timeout.as_millis() + (timeout.subsec_nanos() % 1_000_000 != 0) as u128;
+            millis.min(c_int::MAX as u128) as c_int
+        }
+        None => -1,
+    };
+
+    let rc = libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout);
+
+    if rc == -1 {
+        // Interrupted by a signal; the caller will simply poll again.
+        return std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted;
+    }
+
+    if fds[0].revents != 0 {
+        run_mailbox(context_data);
+    }
+
+    for (port, fd) in ports.into_iter().zip(&fds[1..]) {
+        if fd.revents != 0 {
+            process_port(ctx, port);
+        }
+    }
+
+    if timer_deadline.is_some() {
+        timer::run_due_timers(context_data);
+    }
+
+    !(*context_data).quit
+}
+
+/// Return whether the context still has requests or tasks in progress.
+///
+/// # Safety
+/// Must be called on the context's own thread, with valid context data.
+pub(crate) unsafe fn has_work_in_flight(context_data: *mut ContextData) -> bool {
+    !(*context_data).detached_requests.is_empty()
+        || !(*context_data).pending_bodies.is_empty()
+        || !(*context_data).tasks.is_empty()
+}
+
+/// Keep processing the context's events after it was asked to quit, until it
+/// has no more work in flight or the drain period ends.
+///
+/// # Safety
+/// Must be called on the context's own thread, while no references to the
+/// context's data are alive.
+pub(crate) unsafe fn drain_context(ctx: *mut nxt_unit_ctx_t, drain_period: Duration) {
+    let context_data = (*ctx).data as *mut ContextData;
+    let deadline = Instant::now() + drain_period;
+
+    // An error while draining sets the flag again, and stops the loop.
+    (*context_data).quit = false;
+
+    while has_work_in_flight(context_data) {
+        let now = Instant::now();
+
+        if now >= deadline || !poll_context(ctx, Some(deadline - now)) {
+            break;
+        }
+    }
+
+    (*context_data).quit = true;
+}
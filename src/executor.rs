@@ -0,0 +1,557 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::Duration;
+
+use libc::c_void;
+
+use crate::detached::DetachedRequest;
+use crate::error::UnitResult;
+use crate::event_loop::ContextMailbox;
+use crate::nxt_unit::{self, nxt_unit_request_info_t};
+use crate::request::Request;
+use crate::unit::{ContextData, HeadersAction, UnitService};
+
+// Each Unit context has its own executor, which polls its futures from inside
+// the context's event loop, on the context's thread. Futures are therefore not
+// required to be Send, and can freely use Request, Response and BodyWriter
+// objects.
+
+pub(crate) type LocalTask = Pin<Box<dyn Future<Output = ()>>>;
+
+/// A request that is being handled by an [`AsyncUnitService`].
+///
+/// This is a reference-counted handle to a detached request, which may be
+/// cloned and held across `.await` points. It is not [`Send`] nor [`Sync`],
+/// so it can only be used by futures running on the request's context.
+///
+/// The request is finished with the result of the future returned by
+/// [`AsyncUnitService::handle_request()`].
+#[derive(Clone)]
+pub struct AsyncRequest {
+    detached_request: Rc<DetachedRequest>,
+}
+
+impl AsyncRequest {
+    /// Access the request, in order to inspect it or send (parts of) a
+    /// response.
+    ///
+    /// Returns `None` if the request was already closed, e.g. because the
+    /// client disconnected.
+    pub fn request(&self) -> Option<Request<'_>> {
+        self.detached_request.request()
+    }
+
+    /// Return whether or not the request was closed, either because the
+    /// client disconnected or because the Unit server is quitting.
+    pub fn is_closed(&self) -> bool {
+        self.detached_request.is_closed()
+    }
+
+    /// Create a reader for the request body, which waits for more data to
+    /// arrive instead of returning a partial body.
+    ///
+    /// This is only useful with an [`AsyncHandler`] that streams the body (see
+    /// [`AsyncHandler::stream_body()`]); otherwise, the whole body is already
+    /// received before the request's future is started.
+    pub fn body(&self) -> AsyncBodyReader {
+        AsyncBodyReader {
+            request: self.clone(),
+        }
+    }
+}
+
+/// A reader for the body of an [`AsyncRequest`], created with
+/// [`AsyncRequest::body()`].
+///
+/// Reading returns the body data as soon as it arrives, and waits (yielding
+/// to the context's event loop) while more data is pending. This allows large
+/// bodies to be processed chunk by chunk, without Unit buffering them whole.
+///
+/// When the `tokio` feature is enabled, this reader also implements
+/// `tokio::io::AsyncRead`.
+pub struct AsyncBodyReader {
+    request: AsyncRequest,
+}
+
+impl AsyncBodyReader {
+    /// Read body data into the buffer, and return the number of bytes read.
+    ///
+    /// Returns 0 once the whole body was read. Returns an error with the
+    /// [`ConnectionAborted`](std::io::ErrorKind::ConnectionAborted) kind if the
+    /// request was closed.
+    pub async fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        std::future::poll_fn(|cx| self.poll_read(cx, buf)).await
+    }
+
+    /// Read the rest of the body into a vector.
+    pub async fn read_to_vec(&mut self) -> std::io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        let mut buf = vec![0u8; 16 * 1024];
+
+        loop {
+            let bytes = self.read(&mut buf).await?;
+
+            if bytes == 0 {
+                return Ok(body);
+            }
+
+            body.extend_from_slice(&buf[..bytes]);
+        }
+    }
+
+    /// Attempt to read body data into the buffer, registering the current
+    /// task to be woken up when more data arrives if none is available.
+    pub fn poll_read(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let req = match self.request.request() {
+            Some(req) => req.nxt_request,
+            None => {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionAborted,
+                    "Request was closed",
+                )))
+            }
+        };
+
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        // SAFETY: The request is not closed, and this object is not Send, so
+        // this runs on the context's thread.
+        unsafe {
+            let bytes = nxt_unit::nxt_unit_request_read(
+                req,
+                buf.as_mut_ptr() as *mut c_void,
+                buf.len() as u64,
+            );
+
+            if bytes < 0 {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "Could not read request body",
+                )));
+            }
+
+            // Unit decreases the remaining content length as it is read.
+            if bytes > 0 || (*req).content_length == 0 {
+                return Poll::Ready(Ok(bytes as usize));
+            }
+
+            let context_data = (*(*req).ctx).data as *mut ContextData;
+
+            if context_data.is_null() {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionAborted,
+                    "Request was closed",
+                )));
+            }
+
+            (*context_data).body_wakers.insert(req, cx.waker().clone());
+        }
+
+        Poll::Pending
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl tokio::io::AsyncRead for AsyncBodyReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let bytes = match this.poll_read(cx, buf.initialize_unfilled()) {
+            Poll::Ready(Ok(bytes)) => bytes,
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            Poll::Pending => return Poll::Pending,
+        };
+        buf.advance(bytes);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A trait that can be implemented by asynchronous request handlers to be used
+/// with [`Unit::set_async_request_handler()`](crate::Unit::set_async_request_handler).
+///
+/// The returned future is executed on the context's thread, by a
+/// single-threaded executor that runs inside the context's event loop. Other
+/// requests will be handled while the future is waiting.
+///
+/// This trait is automatically implemented for functions or lambda functions
+/// that take an [`AsyncRequest`] object and return a future with a
+/// [`UnitResult<()>`](UnitResult) output.
+///
+/// # Example
+///
+/// ```no_run
+/// use unit_rs::{spawn_blocking, AsyncRequest, Unit, UnitError};
+///
+/// fn main() {
+///     let mut unit = Unit::new().unwrap();
+///
+///     unit.set_async_request_handler(|req: AsyncRequest| async move {
+///         // Blocking work runs on another thread, while this context
+///         // handles other requests.
+///         let answer = spawn_blocking(|| 6 * 7).await;
+///
+///         let req = req.request().ok_or_else(UnitError::error)?;
+///         let body = format!("The answer is {}\n", answer);
+///         req.send_response(200, &[("Content-Type", "text/plain")], body)
+///     });
+///
+///     unit.run();
+/// }
+/// ```
+pub trait AsyncUnitService {
+    type Future: Future<Output = UnitResult<()>> + 'static;
+
+    fn handle_request(&mut self, req: AsyncRequest) -> Self::Future;
+}
+
+impl<F, Fut> AsyncUnitService for F
+where
+    F: FnMut(AsyncRequest) -> Fut + 'static,
+    Fut: Future<Output = UnitResult<()>> + 'static,
+{
+    type Future = Fut;
+
+    fn handle_request(&mut self, req: AsyncRequest) -> Self::Future {
+        self(req)
+    }
+}
+
+/// An adapter that runs an [`AsyncUnitService`] as a [`UnitService`], by
+/// detaching each request and spawning its future on the context's executor.
+///
+/// By default, a request's future is started once the whole request body was
+/// received. With [`AsyncHandler::stream_body()`], futures are started as soon
+/// as the request headers arrive, and can read the body as it arrives with
+/// [`AsyncRequest::body()`].
+///
+/// [`Unit::set_async_request_handler()`](crate::Unit::set_async_request_handler)
+/// is a shortcut for using this adapter with its default settings.
+///
+/// # Example
+///
+/// ```no_run
+/// use unit_rs::{AsyncHandler, AsyncRequest, Unit, UnitError};
+///
+/// fn main() {
+///     let mut unit = Unit::new().unwrap();
+///
+///     unit.set_request_handler(
+///         AsyncHandler::new(|req: AsyncRequest| async move {
+///             let mut body = req.body();
+///             let mut buf = vec![0u8; 64 * 1024];
+///             let mut total = 0;
+///
+///             // Chunks are processed as they arrive.
+///             loop {
+///                 let bytes = body.read(&mut buf).await.map_err(|_| UnitError::error())?;
+///                 if bytes == 0 {
+///                     break;
+///                 }
+///                 total += bytes;
+///             }
+///
+///             let req = req.request().ok_or_else(UnitError::error)?;
+///             let response = format!("Received {} bytes\n", total);
+///             req.send_response(200, &[("Content-Type", "text/plain")], response)
+///         })
+///         .stream_body(),
+///     );
+///
+///     unit.run();
+/// }
+/// ```
+pub struct AsyncHandler<S> {
+    service: S,
+    stream_body: bool,
+}
+
+impl<S: AsyncUnitService> AsyncHandler<S> {
+    pub fn new(service: S) -> Self {
+        AsyncHandler {
+            service,
+            stream_body: false,
+        }
+    }
+
+    /// Start each request's future as soon as the request headers arrive,
+    /// instead of waiting for the whole body.
+    pub fn stream_body(mut self) -> Self {
+        self.stream_body = true;
+        self
+    }
+
+    fn spawn_request(&mut self, nxt_request: *mut nxt_unit_request_info_t) {
+        // SAFETY: Requests are only received in the request handler, on the
+        // context's thread.
+        let (context_data, detached_request) = unsafe {
+            (
+                (*(*nxt_request).ctx).data as *mut ContextData,
+                DetachedRequest::new(nxt_request),
+            )
+        };
+
+        let detached_request = Rc::new(detached_request);
+        let future = self.service.handle_request(AsyncRequest {
+            detached_request: detached_request.clone(),
+        });
+
+        let task = Box::pin(async move {
+            let result = future.await;
+            detached_request.finish_ref(result);
+        });
+
+        // SAFETY: The request handler runs on the context's thread, and the
+        // service was taken out of the context data while it runs.
+        unsafe { spawn(context_data, task) };
+    }
+}
+
+impl<S: AsyncUnitService> UnitService for AsyncHandler<S> {
+    fn on_headers(&mut self, req: &Request) -> UnitResult<HeadersAction> {
+        if !self.stream_body {
+            return Ok(HeadersAction::Buffer);
+        }
+
+        // The detached request now owns the request, so `handle_request()`
+        // will not be called.
+        self.spawn_request(req.nxt_request);
+
+        Ok(HeadersAction::Stream)
+    }
+
+    fn handle_request(&mut self, req: Request) -> UnitResult<()> {
+        self.spawn_request(req.nxt_request);
+
+        Ok(())
+    }
+}
+
+pub(crate) struct TaskWaker {
+    id: u64,
+    mailbox: Arc<ContextMailbox>,
+    scheduled: AtomicBool,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if self.scheduled.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let id = self.id;
+
+        // If the context no longer exists, its tasks were already dropped.
+        self.mailbox.post(Box::new(move |context_data| {
+            // SAFETY: Tasks are executed on the context's thread, by its event
+            // loop.
+            unsafe { poll_task(context_data, id) }
+        }));
+    }
+}
+
+/// Spawn a task on the context's executor, and poll it for the first time.
+///
+/// # Safety
+/// Must be called on the context's thread, with valid context data.
+pub(crate) unsafe fn spawn(context_data: *mut ContextData, task: LocalTask) {
+    let id = (*context_data).next_task_id;
+    (*context_data).next_task_id += 1;
+
+    let waker = Arc::new(TaskWaker {
+        id,
+        mailbox: (*context_data).mailbox.clone(),
+        scheduled: AtomicBool::new(false),
+    });
+
+    (*context_data).tasks.insert(id, (task, waker));
+
+    poll_task(context_data, id);
+}
+
+/// Poll a task, and keep it if it is still pending.
+///
+/// # Safety
+/// Must be called on the context's thread, with valid context data.
+unsafe fn poll_task(context_data: *mut ContextData, id: u64) {
+    // The task is taken out of the context data while it is polled, since it
+    // may spawn other tasks or otherwise access the context data.
+    let (mut task, task_waker) = match (*context_data).tasks.remove(&id) {
+        Some(task) => task,
+        None => return,
+    };
+
+    task_waker.scheduled.store(false, Ordering::Release);
+
+    let waker = Waker::from(task_waker.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    if task.as_mut().poll(&mut cx).is_pending() {
+        (*context_data).tasks.insert(id, (task, task_waker));
+    }
+}
+
+/// Run a blocking function on another thread, and return a future that
+/// resolves to its result.
+///
+/// This can be used from an [`AsyncUnitService`] to perform blocking IO or
+/// expensive computations without stalling the other requests of the
+/// context.
+///
+/// The functions run on a pool of at most 64 threads, shared by all contexts.
+/// Threads are started on demand, and exit after being idle for 10 seconds.
+/// When all of them are busy, functions wait in a queue until a thread is
+/// free, so functions that block indefinitely should use their own threads.
+///
+/// # Panics
+/// The returned future panics if the function panicked.
+pub fn spawn_blocking<T, F>(f: F) -> BlockingTask<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let shared = Arc::new(Mutex::new(BlockingState {
+        result: None,
+        waker: None,
+    }));
+    let thread_shared = shared.clone();
+
+    run_blocking(Box::new(move || {
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+
+        let mut state = thread_shared
+            .lock()
+            .expect("Blocking task should not be poisoned");
+        state.result = Some(result.map_err(|_| ()));
+
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }));
+
+    BlockingTask { shared }
+}
+
+const MAX_BLOCKING_THREADS: usize = 64;
+const BLOCKING_THREAD_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+type BlockingJob = Box<dyn FnOnce() + Send>;
+
+struct BlockingPool {
+    queue: VecDeque<BlockingJob>,
+    threads: usize,
+    idle_threads: usize,
+}
+
+static BLOCKING_POOL: Mutex<BlockingPool> = Mutex::new(BlockingPool {
+    queue: VecDeque::new(),
+    threads: 0,
+    idle_threads: 0,
+});
+static BLOCKING_POOL_JOBS: Condvar = Condvar::new();
+
+/// Queue a job on the blocking pool, and start a new thread for it if all
+/// threads are busy and the pool is not full.
+fn run_blocking(job: BlockingJob) {
+    let mut pool = BLOCKING_POOL
+        .lock()
+        .expect("Blocking pool should not be poisoned");
+
+    pool.queue.push_back(job);
+
+    if pool.idle_threads > 0 {
+        BLOCKING_POOL_JOBS.notify_one();
+    } else if pool.threads < MAX_BLOCKING_THREADS {
+        pool.threads += 1;
+        drop(pool);
+
+        let spawned = std::thread::Builder::new()
+            .name("unit-rs-blocking".to_string())
+            .spawn(blocking_worker);
+
+        if let Err(err) = spawned {
+            BLOCKING_POOL
+                .lock()
+                .expect("Blocking pool should not be poisoned")
+                .threads -= 1;
+            panic!("Failed to spawn blocking thread: {}", err);
+        }
+    }
+}
+
+fn blocking_worker() {
+    let mut pool = BLOCKING_POOL
+        .lock()
+        .expect("Blocking pool should not be poisoned");
+
+    loop {
+        if let Some(job) = pool.queue.pop_front() {
+            drop(pool);
+            // Jobs catch their own panics.
+            job();
+            pool = BLOCKING_POOL
+                .lock()
+                .expect("Blocking pool should not be poisoned");
+            continue;
+        }
+
+        pool.idle_threads += 1;
+        let (guard, wait_result) = BLOCKING_POOL_JOBS
+            .wait_timeout(pool, BLOCKING_THREAD_IDLE_TIMEOUT)
+            .expect("Blocking pool should not be poisoned");
+        pool = guard;
+        pool.idle_threads -= 1;
+
+        if wait_result.timed_out() && pool.queue.is_empty() {
+            pool.threads -= 1;
+            return;
+        }
+    }
+}
+
+struct BlockingState<T> {
+    result: Option<Result<T, ()>>,
+    waker: Option<Waker>,
+}
+
+/// A future returned by [`spawn_blocking()`].
+pub struct BlockingTask<T> {
+    shared: Arc<Mutex<BlockingState<T>>>,
+}
+
+impl<T> Future for BlockingTask<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut state = self
+            .shared
+            .lock()
+            .expect("Blocking task should not be poisoned");
+
+        match state.result.take() {
+            Some(Ok(result)) => Poll::Ready(result),
+            Some(Err(())) => panic!("Blocking task panicked"),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
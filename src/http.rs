@@ -24,14 +24,57 @@
 //! }
 //! ```
 
+use std::net::IpAddr;
 use std::panic::{AssertUnwindSafe, RefUnwindSafe, UnwindSafe};
 
-use http::{uri::PathAndQuery, Uri};
+use http::{
+    header::{HeaderName, HeaderValue},
+    uri::PathAndQuery,
+    Method, Uri,
+};
 
-use crate::{request::LogLevel, unit::UnitService, UnitError, UnitResult};
+use crate::{
+    request::LogLevel, shared::SharedUnitService, unit::UnitService, UnitError, UnitResult,
+};
 
 pub use http::{Request, Response};
 
+/// Information about the client's connection, added by [`HttpHandler`] to the
+/// extensions of each [`Request`].
+///
+/// # Example
+///
+/// ```no_run
+/// use http::{Request, Response};
+/// use unit_rs::{http::ConnectionInfo, http::HttpHandler, Unit};
+///
+/// fn main() {
+///     let mut unit = Unit::new().unwrap();
+///
+///     unit.set_request_handler(HttpHandler::new(|req: Request<Vec<u8>>| {
+///         let info = req.extensions().get::<ConnectionInfo>().unwrap();
+///         let body = format!("Hello, {:?}!\n", info.remote_addr);
+///
+///         Ok(Response::builder().body(body.into_bytes())?)
+///     }));
+///
+///     unit.run();
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// See [`Request::remote_addr()`](crate::Request::remote_addr).
+    pub remote_addr: Option<IpAddr>,
+    /// See [`Request::local_addr()`](crate::Request::local_addr).
+    pub local_addr: Option<IpAddr>,
+    /// See [`Request::host()`](crate::Request::host).
+    pub host: String,
+    /// See [`Request::port()`](crate::Request::port).
+    pub port: Option<u16>,
+    /// See [`Request::scheme()`](crate::Request::scheme).
+    pub scheme: &'static str,
+}
+
 /// A trait for request handlers that uses types from the [`http`] crate.
 ///
 /// [`http`]: https://docs.rs/http
@@ -49,7 +92,9 @@ pub trait HttpService: UnwindSafe {
 ///
 /// [`http`]: https://docs.rs/http
 ///
-/// The inner request handler must implement the [`HttpService`] trait.
+/// The inner request handler must implement the [`HttpService`] trait. If it
+/// is also [`Send`] and [`Sync`], the adapter can be shared between contexts
+/// as a [`SharedUnitService`].
 pub struct HttpHandler<H: HttpService>(H);
 
 impl<H: HttpService> HttpHandler<H> {
@@ -67,31 +112,54 @@ impl<H: HttpService + RefUnwindSafe> UnitService for HttpHandler<H> {
     }
 }
 
+impl<H: HttpService + RefUnwindSafe + Send + Sync> SharedUnitService for HttpHandler<H> {
+    fn handle_request(&self, mut req: crate::request::Request<'_>) -> UnitResult<()> {
+        self.handle_request_with_http(&mut req).map_err(|err| {
+            req.log(LogLevel::Error, err.to_string());
+            UnitError::error()
+        })
+    }
+}
+
 impl<H: HttpService + RefUnwindSafe> HttpHandler<H> {
     fn handle_request_with_http(
         &self,
         req: &mut crate::request::Request<'_>,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let path_and_query: PathAndQuery = req.target().parse()?;
+        let path_and_query = PathAndQuery::try_from(req.target_bytes())?;
         let uri = Uri::builder()
-            .scheme(if req.tls() { "https" } else { "http" })
-            .authority(req.server_name())
+            .scheme(req.scheme())
+            .authority(req.server_name_bytes())
             .path_and_query(path_and_query)
             .build()?;
         let mut http_request_builder = Request::builder();
 
-        for (name, value) in req.fields() {
-            http_request_builder = http_request_builder.header(name, value);
+        // Raw bytes are used so that header values that are not valid UTF-8
+        // are preserved.
+        for (name, value) in req.fields_bytes() {
+            http_request_builder = http_request_builder.header(
+                HeaderName::from_bytes(name)?,
+                HeaderValue::from_bytes(value)?,
+            );
         }
 
+        let connection_info = ConnectionInfo {
+            remote_addr: req.remote_addr(),
+            local_addr: req.local_addr(),
+            host: req.host().into_owned(),
+            port: req.port(),
+            scheme: req.scheme(),
+        };
+
         let http_request = http_request_builder
+            .extension(connection_info)
             .uri(uri)
-            .method(req.method())
+            .method(Method::from_bytes(req.method_bytes())?)
             .body(req.body().read_to_vec()?)?;
 
         // SAFETY:
         // The only !UnwindSafe part of http::Request is its Extensions, and
-        // this library does not add any extensions to it.
+        // this library only adds a ConnectionInfo to it, which is UnwindSafe.
         let http_request = AssertUnwindSafe(http_request);
         let handler = &self.0;
 
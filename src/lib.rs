@@ -31,35 +31,110 @@
 //! inspect all aspects of a request and create a response.
 //!
 //! This library is also capable of multi-threading by creating additional
-//! instances of [`Unit`].
+//! instances of [`Unit`], or with a pool of threads started by
+//! [`Unit::run_threads()`]. Handlers that implement [`SharedUnitService`] can
+//! be shared by all threads with [`Unit::set_shared_handler()`].
 //!
 //! When the `http` feature enabled, the [`http::HttpHandler`] adapter can be
 //! used to write handlers using types from the [`http`](https://docs.rs/http)
 //! crate.
 //!
-//! ## Missing features
+//! Requests can be upgraded to WebSocket connections with
+//! [`Request::upgrade_websocket()`]; see the [`websocket`] module.
 //!
-//! WebSockets support is not yet implemented.
+//! Requests can be detached from their handler with [`Request::detach()`],
+//! and finished later, even from another thread through a
+//! [`RequestCompleter`].
 //!
-//! A callback for inspecting a request header (and potentially closing the
-//! request) before Unit buffers the whole request body is not yet available.
+//! Asynchronous handlers can be set with [`Unit::set_async_request_handler()`];
+//! their futures run on a single-threaded executor inside each context's
+//! event loop. With [`AsyncHandler::stream_body()`], futures start as soon as
+//! the request headers arrive, and can read the body as it arrives.
 //!
-//! There is currently no way to perform asynchronous handling of requests.
-//! Handlers with expensive computations or blocking IO will block the whole
-//! thread context.
+//! When the `tokio` feature is enabled, `Unit::run_async()` can be used to
+//! handle requests as part of a [`tokio`](https://docs.rs/tokio) runtime.
+//!
+//! Request headers can be inspected before Unit buffers the whole request
+//! body with [`UnitService::on_headers()`], e.g. in order to reject uploads
+//! that are too large.
+//!
+//! A [`UnitBuilder`] created with [`Unit::builder()`] can tune `libunit`'s
+//! options, such as its shared memory limit or the number of requests after
+//! which the process is recycled, and set callbacks for when the application
+//! becomes ready or quits. It can also set a drain period during which
+//! in-flight requests can still complete after Unit asks the application to
+//! quit.
+//!
+//! All contexts can be stopped from any thread with a [`UnitQuitHandle`],
+//! obtained through [`Unit::quit_handle()`].
+//!
+//! By default, panics in handlers are resumed on the context's thread; with
+//! [`Unit::set_panic_policy()`], a context can instead respond with a 500
+//! error and keep serving, or abort the process. See [`PanicPolicy`].
+//!
+//! For custom scheduling, [`Unit::run_ctx()`] and [`Unit::run_shared()`]
+//! process a context's existing requests separately from new ones, and
+//! [`Unit::dequeue_request()`] reads new requests without handling them.
+//!
+//! In order to embed Unit in another event loop, [`Unit::run_once()`] and
+//! [`Unit::run_for()`] handle requests for a limited time, and
+//! [`Unit::poll_fds()`] returns the file descriptors to wait for. When the
+//! `mio` feature is enabled, a [`Unit`] can be registered with a
+//! [`mio`](https://docs.rs/mio) registry.
+//!
+//! Callbacks can be scheduled on a context's own thread with
+//! [`Unit::schedule_once()`] and [`Unit::schedule_interval()`]; they run in
+//! between request handlers, and can share the handler's `!Send` state.
+//!
+//! Request properties and headers that are not valid UTF-8 are converted
+//! lossily by accessors such as [`Request::path()`]; their original bytes are
+//! available through variants such as [`Request::path_bytes()`].
+//!
+//! When Unit's configuration defines several `targets` for the application, a
+//! [`TargetRouter`] can dispatch each request to a handler for its target.
+//!
+//! A [`Router`] dispatches requests by method and path, with `:param` and
+//! `*wildcard` segments and nested routers, and answers unmatched requests
+//! with 404 or 405 responses.
 
 #![cfg_attr(docsrs, feature(doc_cfg))]
 
+mod builder;
+mod detached;
 mod error;
+mod event_loop;
+mod executor;
 #[cfg(feature = "http")]
 #[cfg_attr(docsrs, doc(cfg(feature = "http")))]
 pub mod http;
+#[cfg(feature = "mio")]
+mod mio_source;
 mod nxt_unit;
+mod panic;
+mod queue;
 mod request;
 mod response;
+mod router;
+mod shared;
+mod target_router;
+mod timer;
+#[cfg(feature = "tokio")]
+mod tokio_loop;
 mod unit;
+pub mod websocket;
 
-pub use error::{UnitError, UnitInitError, UnitResult};
-pub use request::{BodyReader, Request};
+pub use builder::UnitBuilder;
+pub use detached::{DetachedRequest, RequestCompleter};
+pub use error::{UnitBuildError, UnitError, UnitInitError, UnitResult};
+pub use executor::{
+    spawn_blocking, AsyncBodyReader, AsyncHandler, AsyncRequest, AsyncUnitService, BlockingTask,
+};
+pub use panic::PanicPolicy;
+pub use queue::QueuedRequest;
+pub use request::{BodyReader, LogLevel, Request};
 pub use response::{BodyWriter, Response};
-pub use unit::{Unit, UnitService};
+pub use router::{Params, RouteHandler, Router};
+pub use shared::{SharedHandler, SharedUnitService};
+pub use target_router::TargetRouter;
+pub use timer::TimerHandle;
+pub use unit::{HeadersAction, Unit, UnitQuitHandle, UnitService};
@@ -0,0 +1,95 @@
+use std::os::unix::io::RawFd;
+
+use mio::event::Source;
+use mio::unix::SourceFd;
+use mio::{Interest, Registry, Token};
+
+use crate::unit::Unit;
+
+/// A [`Unit`] context can be registered with a [`mio`] registry, in order to
+/// be notified when [`Unit::run_once()`] has work to do.
+///
+/// All of the context's [file descriptors](Unit::poll_fds) are registered with
+/// the same token. Since Unit may add or remove ports while processing events,
+/// the context should be reregistered after each call to
+/// [`Unit::run_once()`], which will only update the registrations that
+/// changed.
+///
+/// Although mio's events are edge-triggered, [`Unit::run_once()`] processes
+/// all of the pending work at once, so calling it with a zero timeout after
+/// each event is enough.
+///
+/// Note that Unit's shared port is read by all contexts; two contexts cannot
+/// be registered with the same registry.
+#[cfg_attr(docsrs, doc(cfg(feature = "mio")))]
+impl Source for Unit {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> std::io::Result<()> {
+        if !self.is_active() {
+            return Ok(());
+        }
+
+        let fds = self.poll_fds();
+
+        for fd in &fds {
+            SourceFd(fd).register(registry, token, interests)?;
+        }
+
+        self.context_data_mut().mio_fds = fds;
+        Ok(())
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> std::io::Result<()> {
+        if !self.is_active() {
+            return Ok(());
+        }
+
+        let old_fds = std::mem::take(&mut self.context_data_mut().mio_fds);
+        let fds = self.poll_fds();
+
+        // File descriptors of removed ports may already be closed, which also
+        // removes them from the registry.
+        for fd in old_fds.iter().filter(|fd| !fds.contains(fd)) {
+            let _ = SourceFd(fd).deregister(registry);
+        }
+
+        for fd in &fds {
+            if old_fds.contains(fd) {
+                SourceFd(fd).reregister(registry, token, interests)?;
+            } else {
+                SourceFd(fd).register(registry, token, interests)?;
+            }
+        }
+
+        self.context_data_mut().mio_fds = fds;
+        Ok(())
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> std::io::Result<()> {
+        if !self.is_active() {
+            return Ok(());
+        }
+
+        let old_fds: Vec<RawFd> = std::mem::take(&mut self.context_data_mut().mio_fds);
+        let fds = self.poll_fds();
+
+        for fd in &old_fds {
+            let result = SourceFd(fd).deregister(registry);
+
+            if fds.contains(fd) {
+                result?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+use std::any::Any;
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::cell::RefCell;
+use std::sync::Once;
+
+use libc::c_char;
+
+use crate::nxt_unit::{self, nxt_unit_ctx_t};
+use crate::unit::ContextData;
+
+/// What a [`Unit`](crate::Unit) context does when one of its request or
+/// WebSocket handlers panics.
+///
+/// The policy can be set with
+/// [`Unit::set_panic_policy()`](crate::Unit::set_panic_policy) or
+/// [`UnitBuilder::panic_policy()`](crate::UnitBuilder::panic_policy).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PanicPolicy {
+    /// Finish the request with an error, stop the context's event loop, and
+    /// resume the panic from [`Unit::run()`](crate::Unit::run).
+    #[default]
+    Propagate,
+    /// Respond with a 500 error if no response was sent yet, log the panic
+    /// through Unit's log, and keep handling requests. WebSocket connections
+    /// are closed instead.
+    ///
+    /// The panic's backtrace is logged as well if backtraces are enabled, e.g.
+    /// with the `RUST_BACKTRACE` environment variable. In order to capture
+    /// it, a panic hook is installed in front of the current one, which is
+    /// still called for every panic; see
+    /// [`Unit::set_panic_policy()`](crate::Unit::set_panic_policy).
+    Respond500AndContinue,
+    /// Log the panic through Unit's log, and abort the process. The Unit
+    /// server will start a new process in its place.
+    AbortProcess,
+}
+
+thread_local! {
+    static LAST_BACKTRACE: RefCell<Option<Backtrace>> = const { RefCell::new(None) };
+}
+
+static BACKTRACE_HOOK: Once = Once::new();
+
+/// Install a panic hook that remembers the backtrace of the last panic on each
+/// thread, so that it can be logged after the panic is caught.
+///
+/// The hook is only installed once per process. It chains to the hook that
+/// was installed before it (e.g. one from an error reporting library), which
+/// is still called for every panic, so it does not replace it.
+pub(crate) fn install_backtrace_hook() {
+    BACKTRACE_HOOK.call_once(|| {
+        let previous_hook = std::panic::take_hook();
+
+        std::panic::set_hook(Box::new(move |info| {
+            let backtrace = Backtrace::capture();
+            LAST_BACKTRACE.with(|last| *last.borrow_mut() = Some(backtrace));
+            previous_hook(info);
+        }));
+    });
+}
+
+/// Return the message of a panic, if it has one.
+pub(crate) fn payload_message(panic_payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic_payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic_payload.downcast_ref::<String>() {
+        message
+    } else {
+        "Box<dyn Any>"
+    }
+}
+
+/// Handle a panic caught from a callback or task that has nothing left to
+/// clean up, according to the context's policy.
+///
+/// Panics are never resumed here, since Unit's C frames may be on the stack;
+/// instead, the first panic is stored and the context is asked to quit, so
+/// that [`Unit::run()`](crate::Unit::run) can resume it.
+///
+/// # Safety
+/// Must be called on the context's thread, with valid context data.
+pub(crate) unsafe fn handle_panic(
+    context_data: *mut ContextData,
+    what: &str,
+    panic_payload: Box<dyn Any + Send>,
+) {
+    let ctx = (*context_data).ctx;
+
+    match (*context_data).panic_policy {
+        PanicPolicy::Propagate => {
+            if (*context_data).panic_payload.is_none() {
+                (*context_data).panic_payload = Some(panic_payload);
+            }
+            (*context_data).quit = true;
+        }
+        PanicPolicy::Respond500AndContinue => log_panic(ctx, what, panic_payload.as_ref()),
+        PanicPolicy::AbortProcess => {
+            log_panic(ctx, what, panic_payload.as_ref());
+            std::process::abort()
+        }
+    }
+}
+
+/// Log a caught panic, and its backtrace if one was captured, through Unit's
+/// log.
+///
+/// # Safety
+/// The context must be valid, and belong to the current thread.
+unsafe fn log_panic(ctx: *mut nxt_unit_ctx_t, what: &str, panic_payload: &(dyn Any + Send)) {
+    let mut message = format!("{} panicked: {}", what, payload_message(panic_payload));
+
+    if let Some(backtrace) = LAST_BACKTRACE.with(|last| last.borrow_mut().take()) {
+        if backtrace.status() == BacktraceStatus::Captured {
+            message.push_str("\nstack backtrace:\n");
+            message.push_str(&backtrace.to_string());
+        }
+    }
+
+    nxt_unit::nxt_unit_log(
+        ctx,
+        nxt_unit::NXT_UNIT_LOG_ALERT as i32,
+        "%.*s\0".as_ptr() as *const c_char,
+        message.len() as i32,
+        message.as_ptr(),
+    );
+}
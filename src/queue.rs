@@ -0,0 +1,125 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use crate::nxt_unit::{self, nxt_unit_request_info_t};
+use crate::request::Request;
+use crate::unit::{self, ContextData};
+
+/// A request that was read from Unit's shared queue with
+/// [`Unit::dequeue_request()`](crate::Unit::dequeue_request), but not yet
+/// handled.
+///
+/// The request can be inspected in order to decide when to handle it, and is
+/// then passed to the context's request handler with
+/// [`QueuedRequest::dispatch()`]. This allows building custom schedulers, e.g.
+/// ones that serve health checks ahead of other requests.
+///
+/// This object is not [`Send`] nor [`Sync`]; it must be dispatched on the
+/// thread of the [`Unit`](crate::Unit) context that dequeued it, and not from
+/// inside that context's request handler.
+///
+/// If this object is dropped without being dispatched, the request is finished
+/// with an error.
+pub struct QueuedRequest {
+    nxt_request: *mut nxt_unit_request_info_t,
+    // Set when the request was finished by its context, either because the
+    // client disconnected or because the context was dropped.
+    finished: Rc<Cell<bool>>,
+}
+
+impl QueuedRequest {
+    /// # Safety
+    /// The request must have been returned by `nxt_unit_dequeue_request()` on
+    /// the current thread's context, and not yet handled.
+    pub(crate) unsafe fn new(nxt_request: *mut nxt_unit_request_info_t) -> Self {
+        let context_data = (*(*nxt_request).ctx).data as *mut ContextData;
+        let finished = Rc::new(Cell::new(false));
+
+        // See unit::request_handler().
+        nxt_unit::nxt_unit_request_group_dup_fields(nxt_request);
+
+        (*context_data)
+            .queued_requests
+            .insert(nxt_request, finished.clone());
+
+        QueuedRequest {
+            nxt_request,
+            finished,
+        }
+    }
+
+    /// Take the request out of its context's queue.
+    ///
+    /// Returns false if the request was already finished.
+    fn unqueue(&self) -> bool {
+        if self.finished.get() {
+            return false;
+        }
+
+        // SAFETY: The request was not finished, so its context is still alive.
+        // Queued requests are not Send, so this runs on the context's thread.
+        unsafe {
+            let context_data = (*(*self.nxt_request).ctx).data as *mut ContextData;
+            (*context_data).queued_requests.remove(&self.nxt_request);
+        }
+
+        self.finished.set(true);
+        true
+    }
+
+    /// Access the request, in order to inspect its method, path and headers.
+    ///
+    /// The request body may still be incomplete.
+    ///
+    /// Returns `None` if the client disconnected in the meantime, or if the
+    /// Unit context that dequeued the request no longer exists.
+    pub fn request(&self) -> Option<Request<'_>> {
+        if self.finished.get() {
+            return None;
+        }
+
+        Some(Request {
+            nxt_request: self.nxt_request,
+            _lifetime: Default::default(),
+        })
+    }
+
+    /// Handle the request with the context's request handler, as if it was
+    /// just received.
+    ///
+    /// # Panics
+    /// With the default [`PanicPolicy`](crate::PanicPolicy), if the handler
+    /// panics, the panic is resumed from this method.
+    pub fn dispatch(self) {
+        if !self.unqueue() {
+            return;
+        }
+
+        let nxt_request = self.nxt_request;
+
+        // SAFETY: The request was not handled yet, and its context is still
+        // alive. Queued requests are not Send, so this runs on the context's
+        // thread.
+        unsafe {
+            let context_data = (*(*nxt_request).ctx).data as *mut ContextData;
+
+            unit::handle_new_request(nxt_request);
+
+            if let Some(panic_payload) = (*context_data).panic_payload.take() {
+                std::panic::resume_unwind(panic_payload);
+            }
+        }
+    }
+}
+
+impl Drop for QueuedRequest {
+    fn drop(&mut self) {
+        if self.unqueue() {
+            // SAFETY: The request was not handled, and its context is still
+            // alive.
+            unsafe {
+                nxt_unit::nxt_unit_request_done(self.nxt_request, nxt_unit::NXT_UNIT_ERROR as i32)
+            };
+        }
+    }
+}
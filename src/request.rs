@@ -1,11 +1,17 @@
+use std::borrow::Cow;
 use std::io::Read;
 use std::marker::PhantomData;
+use std::net::IpAddr;
 
-use libc::c_void;
+use libc::{c_char, c_void};
 
+use crate::detached::{self, DetachedRequest};
 use crate::error::{IntoUnitResult, UnitResult};
-use crate::nxt_unit::{self, nxt_unit_request_info_t, nxt_unit_sptr_get, nxt_unit_sptr_t};
+use crate::nxt_unit::{
+    self, nxt_unit_field_t, nxt_unit_request_info_t, nxt_unit_sptr_get, nxt_unit_sptr_t,
+};
 use crate::response::Response;
+use crate::websocket::{self, WebSocket, WebSocketHandler};
 use crate::{BodyWriter, UnitError};
 
 /// A request received by the NGINX Unit server.
@@ -194,8 +200,87 @@ impl<'a> Request<'a> {
         }
     }
 
+    /// Accept a WebSocket handshake request, and upgrade the connection to a
+    /// WebSocket.
+    ///
+    /// This sends a `101 Switching Protocols` response, so no other response
+    /// may be sent for this request. Afterwards, each frame received from the
+    /// client will be passed to the given handler, on the same thread as this
+    /// request.
+    ///
+    /// The request stays open after the request handler returns, until the
+    /// connection is closed. Returns an error if the request is not a
+    /// WebSocket handshake.
+    pub fn upgrade_websocket(
+        &self,
+        handler: impl WebSocketHandler + 'static,
+    ) -> UnitResult<WebSocket> {
+        // SAFETY: Unit's C API will return an error if a response was already
+        // sent. The context data is only accessed from this thread, and is not
+        // borrowed while the request handler runs.
+        unsafe {
+            if nxt_unit::nxt_unit_request_is_websocket_handshake(self.nxt_request) == 0 {
+                return Err(UnitError::error());
+            }
+
+            nxt_unit::nxt_unit_response_init(self.nxt_request, 101, 0, 0).into_unit_result()?;
+            nxt_unit::nxt_unit_response_upgrade(self.nxt_request).into_unit_result()?;
+            nxt_unit::nxt_unit_response_send(self.nxt_request).into_unit_result()?;
+
+            Ok(websocket::register(self.nxt_request, Box::new(handler)))
+        }
+    }
+
+    /// Detach the request from the request handler, so that it can be
+    /// finished after the handler returns.
+    ///
+    /// The request will not be finished when the request handler returns;
+    /// instead, it is finished when the returned [`DetachedRequest`] is
+    /// finished or dropped. See [`DetachedRequest::into_completer()`] for
+    /// finishing the request from another thread.
+    ///
+    /// This should not be used on requests that were upgraded to a WebSocket,
+    /// which already stay alive until the connection is closed.
+    pub fn detach(self) -> DetachedRequest {
+        // SAFETY: Requests are only received in the request handler, on the
+        // context's thread.
+        unsafe { DetachedRequest::new(self.nxt_request) }
+    }
+
+    /// Return whether or not the client closed the connection, or the Unit
+    /// server is quitting.
+    ///
+    /// This can only happen for requests that outlive their request handler,
+    /// such as the requests of a [`DetachedRequest`] or an
+    /// [`AsyncRequest`](crate::AsyncRequest); long-running responses can use it
+    /// to stop producing data.
+    pub fn is_closed(&self) -> bool {
+        // SAFETY: The request was not finished yet, since this object borrows
+        // it.
+        unsafe { detached::request_is_closed(self.nxt_request) }
+    }
+
     /// Create an interator over all header (name, value) tuples.
-    pub fn fields(&self) -> impl Iterator<Item = (&str, &str)> {
+    ///
+    /// The fields are not listed in the order in which the client sent them:
+    /// when a request is received, repeated headers are moved next to the
+    /// first header with the same name, keeping their relative order. For
+    /// example, `A: 1`, `B: 2`, `A: 3` is listed as `A: 1`, `A: 3`, `B: 2`.
+    ///
+    /// Names and values that are not valid UTF-8 have their invalid sequences
+    /// replaced with `U+FFFD REPLACEMENT CHARACTER`; use
+    /// [`Request::fields_bytes()`] to get the original bytes.
+    pub fn fields(&self) -> impl Iterator<Item = (Cow<'_, str>, Cow<'_, str>)> {
+        self.fields_bytes().map(|(name, value)| {
+            (
+                String::from_utf8_lossy(name),
+                String::from_utf8_lossy(value),
+            )
+        })
+    }
+
+    /// Create an interator over all header (name, value) tuples, as raw bytes.
+    pub fn fields_bytes(&self) -> impl Iterator<Item = (&[u8], &[u8])> {
         unsafe {
             let r = &(*(*self.nxt_request).request);
 
@@ -208,13 +293,188 @@ impl<'a> Request<'a> {
         }
     }
 
+    /// Return the value of the first header with the given name, compared
+    /// case-insensitively.
+    ///
+    /// Invalid UTF-8 sequences are replaced with `U+FFFD REPLACEMENT
+    /// CHARACTER`; see [`Request::header_bytes()`].
+    pub fn header(&self, name: &str) -> Option<Cow<'_, str>> {
+        self.header_bytes(name).map(String::from_utf8_lossy)
+    }
+
+    /// Return the value of the first header with the given name, compared
+    /// case-insensitively, as raw bytes.
+    pub fn header_bytes(&self, name: &str) -> Option<&[u8]> {
+        self.headers_all_bytes(name).next()
+    }
+
+    /// Create an iterator over the values of all headers with the given name,
+    /// compared case-insensitively, in the order in which they were received.
+    ///
+    /// This is useful for headers that may be repeated, such as `Accept` or
+    /// `Cookie`.
+    ///
+    /// Invalid UTF-8 sequences are replaced with `U+FFFD REPLACEMENT
+    /// CHARACTER`; see [`Request::headers_all_bytes()`].
+    pub fn headers_all(&self, name: &str) -> impl Iterator<Item = Cow<'_, str>> {
+        self.headers_all_bytes(name).map(String::from_utf8_lossy)
+    }
+
+    /// Create an iterator over the values of all headers with the given name,
+    /// compared case-insensitively, as raw bytes.
+    pub fn headers_all_bytes(&self, name: &str) -> impl Iterator<Item = &[u8]> {
+        // SAFETY: The request's fields are not modified while this object
+        // borrows the request.
+        let fields = unsafe {
+            let r = &(*(*self.nxt_request).request);
+            std::slice::from_raw_parts(r.fields.as_ptr(), r.fields_count as usize)
+        };
+
+        // Fields are compared by their hash first, which Unit computes
+        // case-insensitively when receiving the request.
+        let hash = unsafe {
+            nxt_unit::nxt_unit_field_hash(name.as_ptr() as *const c_char, name.len() as u64)
+        };
+        let is_match = move |field: &nxt_unit_field_t, name: &[u8]| {
+            field.hash == hash && unsafe { field_name(field) }.eq_ignore_ascii_case(name)
+        };
+
+        // Repeated fields were grouped together when the request was received,
+        // so all matches follow the first one.
+        let start = fields
+            .iter()
+            .position(|field| is_match(field, name.as_bytes()))
+            .unwrap_or(fields.len());
+        let group = &fields[start..];
+        let first_name = group
+            .first()
+            .map_or(&[][..], |field| unsafe { field_name(field) });
+
+        group
+            .iter()
+            .take_while(move |field| is_match(field, first_name))
+            .map(|field| unsafe { sptr_to_slice(&field.value, field.value_length) })
+    }
+
+    /// Return the length of the request body, as announced by the client's
+    /// `Content-Length` header.
+    ///
+    /// Returns `None` if the request did not have a `Content-Length` header.
+    pub fn content_length(&self) -> Option<u64> {
+        unsafe {
+            let r = &(*(*self.nxt_request).request);
+            if r.content_length_field == nxt_unit::NXT_UNIT_NONE_FIELD {
+                None
+            } else {
+                Some(r.content_length)
+            }
+        }
+    }
+
+    /// Return the value of the request's `Content-Type` header.
+    ///
+    /// Invalid UTF-8 sequences are replaced with `U+FFFD REPLACEMENT
+    /// CHARACTER`; see [`Request::content_type_bytes()`].
+    pub fn content_type(&self) -> Option<Cow<'_, str>> {
+        self.content_type_bytes().map(String::from_utf8_lossy)
+    }
+
+    /// Return the value of the request's `Content-Type` header, as raw bytes.
+    pub fn content_type_bytes(&self) -> Option<&[u8]> {
+        unsafe { self.field_at((*(*self.nxt_request).request).content_type_field) }
+    }
+
+    /// Return the value of the request's `Cookie` header.
+    ///
+    /// Invalid UTF-8 sequences are replaced with `U+FFFD REPLACEMENT
+    /// CHARACTER`; see [`Request::cookie_header_bytes()`].
+    pub fn cookie_header(&self) -> Option<Cow<'_, str>> {
+        self.cookie_header_bytes().map(String::from_utf8_lossy)
+    }
+
+    /// Return the value of the request's `Cookie` header, as raw bytes.
+    pub fn cookie_header_bytes(&self) -> Option<&[u8]> {
+        unsafe { self.field_at((*(*self.nxt_request).request).cookie_field) }
+    }
+
+    /// Return the value of the request's `Authorization` header.
+    ///
+    /// Invalid UTF-8 sequences are replaced with `U+FFFD REPLACEMENT
+    /// CHARACTER`; see [`Request::authorization_bytes()`].
+    pub fn authorization(&self) -> Option<Cow<'_, str>> {
+        self.authorization_bytes().map(String::from_utf8_lossy)
+    }
+
+    /// Return the value of the request's `Authorization` header, as raw bytes.
+    pub fn authorization_bytes(&self) -> Option<&[u8]> {
+        // Grouping repeated fields leaves `authorization_field` stale.
+        self.header_bytes("Authorization")
+    }
+
+    /// Return whether or not the request is a WebSocket handshake, which can
+    /// be accepted with [`Request::upgrade_websocket()`].
+    pub fn is_websocket_handshake(&self) -> bool {
+        unsafe { (*(*self.nxt_request).request).websocket_handshake != 0 }
+    }
+
+    /// Return the index of the application target that the request was routed
+    /// to, in the order of the `targets` object of the application's
+    /// configuration.
+    ///
+    /// Returns 0 if the application does not define any targets. See
+    /// [`TargetRouter`](crate::TargetRouter).
+    pub fn app_target(&self) -> u8 {
+        unsafe { (*(*self.nxt_request).request).app_target }
+    }
+
+    /// Return the part of the request body that Unit received together with
+    /// the request's headers.
+    ///
+    /// This does not consume any data; reading the body with
+    /// [`Request::body()`] still returns the whole body.
+    pub fn preread_content(&self) -> &[u8] {
+        // The preread content lies at the end of the buffer that holds the
+        // request's headers.
+        unsafe {
+            let r = &(*(*self.nxt_request).request);
+            let start = nxt_unit_sptr_get(&r.preread_content) as *const u8;
+            let end = (*(*self.nxt_request).request_buf).end as *const u8;
+            let length = end.offset_from(start).max(0) as usize;
+            std::slice::from_raw_parts(start, length)
+        }
+    }
+
+    /// Return the value of the field with the given index, unless the index
+    /// is `NXT_UNIT_NONE_FIELD`.
+    ///
+    /// # Safety
+    /// The index must be either `NXT_UNIT_NONE_FIELD`, or the index of one of
+    /// the request's fields.
+    unsafe fn field_at(&self, index: u32) -> Option<&[u8]> {
+        if index == nxt_unit::NXT_UNIT_NONE_FIELD {
+            return None;
+        }
+
+        let r = &(*(*self.nxt_request).request);
+        let field = &*r.fields.as_ptr().add(index as usize);
+        Some(sptr_to_slice(&field.value, field.value_length))
+    }
+
     /// Return whether or not the request was encrypted.
     pub fn tls(&self) -> bool {
         unsafe { (*(*self.nxt_request).request).tls != 0 }
     }
 
     /// Return the method of the request (e.g. "GET").
-    pub fn method(&self) -> &str {
+    ///
+    /// Invalid UTF-8 sequences are replaced with `U+FFFD REPLACEMENT
+    /// CHARACTER`; see [`Request::method_bytes()`].
+    pub fn method(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.method_bytes())
+    }
+
+    /// Return the method of the request (e.g. "GET"), as raw bytes.
+    pub fn method_bytes(&self) -> &[u8] {
         unsafe {
             let r = &(*(*self.nxt_request).request);
             sptr_to_slice(&r.method, r.method_length.into())
@@ -222,7 +482,15 @@ impl<'a> Request<'a> {
     }
 
     /// Return the protocol version of the request (e.g. "HTTP/1.1").
-    pub fn version(&self) -> &str {
+    ///
+    /// Invalid UTF-8 sequences are replaced with `U+FFFD REPLACEMENT
+    /// CHARACTER`; see [`Request::version_bytes()`].
+    pub fn version(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.version_bytes())
+    }
+
+    /// Return the protocol version of the request (e.g. "HTTP/1.1"), as raw bytes.
+    pub fn version_bytes(&self) -> &[u8] {
         unsafe {
             let r = &(*(*self.nxt_request).request);
             sptr_to_slice(&r.version, r.version_length.into())
@@ -230,7 +498,15 @@ impl<'a> Request<'a> {
     }
 
     /// Return the remote IP address of the client.
-    pub fn remote(&self) -> &str {
+    ///
+    /// Invalid UTF-8 sequences are replaced with `U+FFFD REPLACEMENT
+    /// CHARACTER`; see [`Request::remote_bytes()`].
+    pub fn remote(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.remote_bytes())
+    }
+
+    /// Return the remote IP address of the client, as raw bytes.
+    pub fn remote_bytes(&self) -> &[u8] {
         unsafe {
             let r = &(*(*self.nxt_request).request);
             sptr_to_slice(&r.remote, r.remote_length.into())
@@ -238,7 +514,15 @@ impl<'a> Request<'a> {
     }
 
     /// Return the local IP address of the server.
-    pub fn local(&self) -> &str {
+    ///
+    /// Invalid UTF-8 sequences are replaced with `U+FFFD REPLACEMENT
+    /// CHARACTER`; see [`Request::local_bytes()`].
+    pub fn local(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.local_bytes())
+    }
+
+    /// Return the local IP address of the server, as raw bytes.
+    pub fn local_bytes(&self) -> &[u8] {
         unsafe {
             let r = &(*(*self.nxt_request).request);
             sptr_to_slice(&r.local, r.local_length.into())
@@ -246,7 +530,15 @@ impl<'a> Request<'a> {
     }
 
     /// Return the host name of the server.
-    pub fn server_name(&self) -> &str {
+    ///
+    /// Invalid UTF-8 sequences are replaced with `U+FFFD REPLACEMENT
+    /// CHARACTER`; see [`Request::server_name_bytes()`].
+    pub fn server_name(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.server_name_bytes())
+    }
+
+    /// Return the host name of the server, as raw bytes.
+    pub fn server_name_bytes(&self) -> &[u8] {
         unsafe {
             let r = &(*(*self.nxt_request).request);
             sptr_to_slice(&r.server_name, r.server_name_length)
@@ -254,7 +546,15 @@ impl<'a> Request<'a> {
     }
 
     /// Return the combined URI path and query string.
-    pub fn target(&self) -> &str {
+    ///
+    /// Invalid UTF-8 sequences are replaced with `U+FFFD REPLACEMENT
+    /// CHARACTER`; see [`Request::target_bytes()`].
+    pub fn target(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.target_bytes())
+    }
+
+    /// Return the combined URI path and query string, as raw bytes.
+    pub fn target_bytes(&self) -> &[u8] {
         unsafe {
             let r = &(*(*self.nxt_request).request);
             sptr_to_slice(&r.target, r.target_length)
@@ -262,7 +562,15 @@ impl<'a> Request<'a> {
     }
 
     /// Return the URI path.
-    pub fn path(&self) -> &str {
+    ///
+    /// Invalid UTF-8 sequences are replaced with `U+FFFD REPLACEMENT
+    /// CHARACTER`; see [`Request::path_bytes()`].
+    pub fn path(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.path_bytes())
+    }
+
+    /// Return the URI path, as raw bytes.
+    pub fn path_bytes(&self) -> &[u8] {
         unsafe {
             let r = &(*(*self.nxt_request).request);
             sptr_to_slice(&r.path, r.path_length)
@@ -270,31 +578,147 @@ impl<'a> Request<'a> {
     }
 
     /// Return the URI query string.
-    pub fn query(&self) -> &str {
+    ///
+    /// Invalid UTF-8 sequences are replaced with `U+FFFD REPLACEMENT
+    /// CHARACTER`; see [`Request::query_bytes()`].
+    pub fn query(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.query_bytes())
+    }
+
+    /// Return the URI query string, as raw bytes.
+    pub fn query_bytes(&self) -> &[u8] {
         unsafe {
             let r = &(*(*self.nxt_request).request);
             sptr_to_slice(&r.query, r.query_length)
         }
     }
 
+    /// Return the remote IP address of the client, if it is a valid IP
+    /// address.
+    ///
+    /// This is `None` for clients connected through a Unix domain socket.
+    pub fn remote_addr(&self) -> Option<IpAddr> {
+        std::str::from_utf8(self.remote_bytes()).ok()?.parse().ok()
+    }
+
+    /// Return the local IP address of the server, if it is a valid IP address.
+    ///
+    /// This is `None` for listeners on a Unix domain socket.
+    pub fn local_addr(&self) -> Option<IpAddr> {
+        std::str::from_utf8(self.local_bytes()).ok()?.parse().ok()
+    }
+
+    /// Return the host name of the server, without its port.
+    ///
+    /// Invalid UTF-8 sequences are replaced with `U+FFFD REPLACEMENT
+    /// CHARACTER`; see [`Request::host_bytes()`].
+    pub fn host(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.host_bytes())
+    }
+
+    /// Return the host name of the server, without its port, as raw bytes.
+    pub fn host_bytes(&self) -> &[u8] {
+        self.split_host().0
+    }
+
+    /// Return the port that the client connected to.
+    ///
+    /// This is the port from the server's host name, or if the client did not
+    /// specify one, the default port of the request's
+    /// [`scheme()`](Request::scheme): 443 for "https", 80 for "http".
+    ///
+    /// Returns `None` if the host name contains a port that is not valid.
+    pub fn port(&self) -> Option<u16> {
+        match self.split_host().1 {
+            Some(port) => parse_port(port),
+            None if self.tls() => Some(443),
+            None => Some(80),
+        }
+    }
+
+    /// Return the scheme of the request, which is either "https" or "http".
+    ///
+    /// The scheme is "https" for encrypted requests, and for requests whose
+    /// host name explicitly specifies port 443, e.g. when TLS is terminated by
+    /// a proxy in front of Unit.
+    pub fn scheme(&self) -> &'static str {
+        let explicit_port = self.split_host().1.and_then(parse_port);
+
+        if self.tls() || explicit_port == Some(443) {
+            "https"
+        } else {
+            "http"
+        }
+    }
+
+    /// Split the server name into its host name and port.
+    fn split_host(&self) -> (&[u8], Option<&[u8]>) {
+        let server_name = self.server_name_bytes();
+
+        let mut name = std::ptr::null_mut();
+        let mut name_length = 0;
+        let mut port = std::ptr::null_mut();
+        let mut port_length = 0;
+
+        // SAFETY: Unit only reads the server name, and returns pointers into
+        // it.
+        unsafe {
+            nxt_unit::nxt_unit_split_host(
+                server_name.as_ptr() as *mut c_char,
+                server_name.len() as u32,
+                &mut name,
+                &mut name_length,
+                &mut port,
+                &mut port_length,
+            );
+
+            let name = if name.is_null() {
+                server_name
+            } else {
+                std::slice::from_raw_parts(name as *const u8, name_length as usize)
+            };
+            let port = if port.is_null() {
+                None
+            } else {
+                Some(std::slice::from_raw_parts(
+                    port as *const u8,
+                    port_length as usize,
+                ))
+            };
+
+            (name, port)
+        }
+    }
+
     /// Log an error message.
     pub fn log<S: AsRef<str>>(&self, level: LogLevel, message: S) {
+        let message = message.as_ref();
+
+        // The message is not nul-terminated, so its length is passed
+        // explicitly.
         unsafe {
             nxt_unit::nxt_unit_req_log(
                 self.nxt_request,
                 level as i32,
-                "%s\0".as_ptr() as *const i8,
-                message.as_ref(),
+                "%.*s\0".as_ptr() as *const c_char,
+                message.len() as i32,
+                message.as_ptr(),
             )
         }
     }
 }
 
-unsafe fn sptr_to_slice(sptr: &nxt_unit_sptr_t, length: u32) -> &str {
+fn parse_port(port: &[u8]) -> Option<u16> {
+    std::str::from_utf8(port).ok()?.parse().ok()
+}
+
+unsafe fn field_name(field: &nxt_unit_field_t) -> &[u8] {
+    sptr_to_slice(&field.name, field.name_length.into())
+}
+
+unsafe fn sptr_to_slice(sptr: &nxt_unit_sptr_t, length: u32) -> &[u8] {
     let ptr = nxt_unit_sptr_get(sptr) as *mut u8;
-    let slice = std::slice::from_raw_parts(ptr, length as usize);
-    // FIXME: temporary, NGINX Unit doesn't guarantee this
-    std::str::from_utf8(slice).unwrap()
+    std::slice::from_raw_parts(ptr, length as usize)
 }
 
 #[repr(u32)]
@@ -310,7 +734,10 @@ pub enum LogLevel {
 /// A reader that reads from the request body.
 ///
 /// This reader is non-blocking, as Unit will buffer the whole request body
-/// before running the request handler.
+/// before running the request handler. When the body is streamed (see
+/// [`HeadersAction::Stream`](crate::HeadersAction::Stream)), it only returns
+/// the data received so far; use an [`AsyncBodyReader`](crate::AsyncBodyReader)
+/// in order to wait for the rest of the body.
 pub struct BodyReader<'a> {
     _lifetime: std::marker::PhantomData<&'a ()>,
     nxt_request: *mut nxt_unit_request_info_t,
@@ -344,3 +771,139 @@ impl std::io::Read for BodyReader<'_> {
         Ok(bytes as usize)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::marker::PhantomData;
+    use std::mem::size_of;
+    use std::os::raw::{c_char, c_void};
+
+    use super::Request;
+    use crate::nxt_unit::{self, nxt_unit_field_t, nxt_unit_request_info_t, nxt_unit_request_t};
+
+    /// A request laid out in memory like the ones received from Unit, with
+    /// the given fields.
+    struct TestRequest {
+        _buf: Vec<u64>,
+        info: Box<nxt_unit_request_info_t>,
+    }
+
+    impl TestRequest {
+        fn new(fields: &[(&str, &str)]) -> Self {
+            let header_size =
+                size_of::<nxt_unit_request_t>() + fields.len() * size_of::<nxt_unit_field_t>();
+            let strings_size: usize = fields
+                .iter()
+                .map(|(name, value)| name.len() + value.len())
+                .sum();
+            let mut buf = vec![0u64; (header_size + strings_size) / 8 + 1];
+
+            unsafe {
+                let base = buf.as_mut_ptr() as *mut u8;
+                let r = base as *mut nxt_unit_request_t;
+
+                (*r).fields_count = fields.len() as u32;
+                (*r).content_length_field = nxt_unit::NXT_UNIT_NONE_FIELD;
+                (*r).content_type_field = nxt_unit::NXT_UNIT_NONE_FIELD;
+                (*r).cookie_field = nxt_unit::NXT_UNIT_NONE_FIELD;
+                (*r).authorization_field = nxt_unit::NXT_UNIT_NONE_FIELD;
+
+                let mut strings = base.add(header_size);
+                for (i, (name, value)) in fields.iter().enumerate() {
+                    let field = (*r).fields.as_mut_ptr().add(i);
+
+                    (*field).hash = nxt_unit::nxt_unit_field_hash(
+                        name.as_ptr() as *const c_char,
+                        name.len() as _,
+                    );
+                    (*field).name_length = name.len() as u8;
+                    (*field).value_length = value.len() as u32;
+
+                    std::ptr::copy_nonoverlapping(name.as_ptr(), strings, name.len());
+                    nxt_unit::nxt_unit_sptr_set(&mut (*field).name, strings as *mut c_void);
+                    strings = strings.add(name.len());
+
+                    std::ptr::copy_nonoverlapping(value.as_ptr(), strings, value.len());
+                    nxt_unit::nxt_unit_sptr_set(&mut (*field).value, strings as *mut c_void);
+                    strings = strings.add(value.len());
+
+                    // Unit's router records the index of these fields.
+                    let index = if name.eq_ignore_ascii_case("Content-Length") {
+                        (*r).content_length = value.parse().unwrap();
+                        &mut (*r).content_length_field
+                    } else if name.eq_ignore_ascii_case("Content-Type") {
+                        &mut (*r).content_type_field
+                    } else if name.eq_ignore_ascii_case("Cookie") {
+                        &mut (*r).cookie_field
+                    } else if name.eq_ignore_ascii_case("Authorization") {
+                        &mut (*r).authorization_field
+                    } else {
+                        continue;
+                    };
+                    *index = i as u32;
+                }
+
+                let mut info: Box<nxt_unit_request_info_t> = Box::new(std::mem::zeroed());
+                info.request = r;
+
+                TestRequest { _buf: buf, info }
+            }
+        }
+
+        fn request(&mut self) -> Request<'_> {
+            Request {
+                nxt_request: &mut *self.info,
+                _lifetime: PhantomData,
+            }
+        }
+    }
+
+    #[test]
+    fn authorization_after_grouping_duplicates() {
+        let mut test_request = TestRequest::new(&[
+            ("Accept", "text/html"),
+            ("Authorization", "Bearer secret"),
+            ("Accept", "text/plain"),
+        ]);
+
+        // Moves the second Accept field before Authorization.
+        unsafe { nxt_unit::nxt_unit_request_group_dup_fields(&mut *test_request.info) };
+
+        let req = test_request.request();
+        assert_eq!(req.authorization().as_deref(), Some("Bearer secret"));
+        assert_eq!(
+            req.headers_all("accept").collect::<Vec<_>>(),
+            ["text/html", "text/plain"]
+        );
+    }
+
+    #[test]
+    fn indexed_fields_after_grouping_duplicates() {
+        let mut test_request = TestRequest::new(&[
+            ("Accept", "text/html"),
+            ("Content-Type", "text/plain"),
+            ("Cookie", "a=1"),
+            ("Content-Length", "5"),
+            ("Accept", "text/plain"),
+        ]);
+
+        // Moves the second Accept field before the indexed fields.
+        unsafe { nxt_unit::nxt_unit_request_group_dup_fields(&mut *test_request.info) };
+
+        let req = test_request.request();
+        assert_eq!(req.content_type().as_deref(), Some("text/plain"));
+        assert_eq!(req.cookie_header().as_deref(), Some("a=1"));
+        assert_eq!(req.content_length(), Some(5));
+        assert_eq!(req.authorization(), None);
+    }
+
+    #[test]
+    fn missing_indexed_fields() {
+        let mut test_request = TestRequest::new(&[("Accept", "text/html")]);
+
+        let req = test_request.request();
+        assert_eq!(req.content_type(), None);
+        assert_eq!(req.cookie_header(), None);
+        assert_eq!(req.content_length(), None);
+    }
+}
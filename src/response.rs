@@ -7,6 +7,7 @@ use crate::nxt_unit::{
     self, nxt_unit_buf_send, nxt_unit_buf_t, nxt_unit_request_info_t, nxt_unit_response_buf_alloc,
 };
 
+use crate::detached;
 use crate::error::{IntoUnitResult, UnitError, UnitResult};
 use crate::request::Request;
 
@@ -81,6 +82,11 @@ impl<'a> Response<'a> {
 ///
 /// The writer will also flush when dropped, but any errors that happen during
 /// a drop will panic.
+///
+/// If the client closes the connection while the writer is used with a
+/// [`DetachedRequest`](crate::DetachedRequest), writing returns errors with
+/// the [`ConnectionAborted`](std::io::ErrorKind::ConnectionAborted) kind, and
+/// dropping the writer discards any unsent data.
 pub struct BodyWriter<'a> {
     _lifetime: std::marker::PhantomData<&'a mut ()>,
     nxt_request: *mut nxt_unit_request_info_t,
@@ -106,7 +112,22 @@ impl<'a> BodyWriter<'a> {
         Ok(writer)
     }
 
+    fn check_open(&self) -> std::io::Result<()> {
+        // SAFETY: The writer borrows the request, so the request was not
+        // finished yet.
+        if unsafe { detached::request_is_closed(self.nxt_request) } {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionAborted,
+                "The client closed the connection",
+            ));
+        }
+
+        Ok(())
+    }
+
     fn allocate_buffer(&mut self) -> std::io::Result<()> {
+        self.check_open()?;
+
         unsafe {
             let buf = nxt_unit_response_buf_alloc(self.nxt_request, self.chunk_size as u32);
 
@@ -165,6 +186,8 @@ impl<'a> BodyWriter<'a> {
 
 impl std::io::Write for BodyWriter<'_> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.check_open()?;
+
         let buf = if buf.len() >= self.bytes_remaining && !buf.is_empty() {
             if self.bytes_remaining == 0 {
                 self.flush()?;
@@ -193,6 +216,8 @@ impl std::io::Write for BodyWriter<'_> {
             return Ok(());
         }
 
+        self.check_open()?;
+
         unsafe {
             (*self.response_buffer).free = (*self.response_buffer)
                 .start
@@ -217,9 +242,16 @@ impl std::io::Write for BodyWriter<'_> {
 impl Drop for BodyWriter<'_> {
     fn drop(&mut self) {
         if !self.chunk_cursor.is_null() {
-            if std::thread::panicking() {
-                unsafe {
-                    nxt_unit::nxt_unit_buf_free(self.response_buffer);
+            // SAFETY: The writer borrows the request, so the request was not
+            // finished yet.
+            let closed = unsafe { detached::request_is_closed(self.nxt_request) };
+
+            if std::thread::panicking() || closed {
+                // The buffer was already sent if it was flushed.
+                if !self.response_buffer.is_null() {
+                    unsafe {
+                        nxt_unit::nxt_unit_buf_free(self.response_buffer);
+                    }
                 }
             } else {
                 if let Err(err) = self.flush() {
@@ -0,0 +1,655 @@
+use crate::error::UnitResult;
+use crate::request::Request;
+use crate::unit::{HeadersAction, UnitService};
+
+/// Parameters captured from the request path by a [`Router`].
+///
+/// For a route such as `/users/:id/*rest`, the `id` parameter contains one
+/// path segment, and the `rest` parameter contains the rest of the path,
+/// without its leading slash.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Params {
+    params: Vec<(String, String)>,
+}
+
+impl Params {
+    /// Return the value of the parameter with the given name.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(param_name, _)| param_name == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Create an iterator over all (name, value) tuples, in the order in which
+    /// they appear in the path.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.params
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.params.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.params.is_empty()
+    }
+}
+
+/// A trait that can be implemented by the handlers of a [`Router`]'s routes.
+///
+/// This trait is automatically implemented for functions or lambda functions
+/// that take a [`Request`] object and the route's [`Params`], and return a
+/// [`UnitResult<()>`](UnitResult).
+pub trait RouteHandler {
+    fn handle_request(&mut self, req: Request, params: &Params) -> UnitResult<()>;
+}
+
+impl<F> RouteHandler for F
+where
+    F: FnMut(Request, &Params) -> UnitResult<()> + 'static,
+{
+    fn handle_request(&mut self, req: Request, params: &Params) -> UnitResult<()> {
+        self(req, params)
+    }
+}
+
+enum Segment {
+    Literal(String),
+    Param(String),
+    Wildcard(String),
+}
+
+enum Entry {
+    Route {
+        // None matches any method.
+        method: Option<String>,
+        pattern: Vec<Segment>,
+        handler: Box<dyn RouteHandler>,
+    },
+    Mount {
+        prefix: Vec<Segment>,
+        router: Router,
+    },
+}
+
+/// The outcome of looking up a request in a router.
+#[derive(Debug, PartialEq, Eq)]
+enum Resolution {
+    /// The indexes of the entries leading to the route's handler.
+    Found(Vec<usize>, Params),
+    /// The path matched, but only for these methods.
+    MethodNotAllowed(Vec<String>),
+    NotFound,
+}
+
+/// A request handler that dispatches requests to other handlers, based on
+/// their method and path.
+///
+/// Routes are matched in the order in which they were added. Route patterns
+/// consist of path segments, each of which can be:
+/// * a literal, such as `users`, which must match exactly;
+/// * a parameter, such as `:id`, which matches any single segment;
+/// * a wildcard, such as `*path`, which matches the rest of the path, and
+///   must be the last segment. The rest of the path may be empty, and its
+///   decoded segments are joined with `/`.
+///
+/// Captured parameters are passed to the handler alongside the request, as
+/// [`Params`].
+///
+/// Routes are matched against the path of the request's target, as sent by
+/// the client. Each segment is percent-decoded before being matched, so
+/// patterns and captured parameters use decoded text (e.g. `a b` for
+/// `a%20b`). `.` and `..` segments are resolved, and a trailing slash is
+/// ignored, so `/users/1/` matches `/users/:id`.
+///
+/// Other routers can be mounted at a prefix with [`Router::mount()`]; their
+/// routes then match the rest of the path after the prefix.
+///
+/// If no route matches the path, the router responds with `404 Not Found`.
+/// If routes match the path but not the method, it responds with
+/// `405 Method Not Allowed`, with an `Allow` header that lists the methods
+/// of those routes. These responses are sent as soon as the request's
+/// headers arrive, without waiting for the body.
+///
+/// # Example
+///
+/// ```no_run
+/// use unit_rs::{Params, Request, Router, Unit};
+///
+/// fn main() {
+///     let mut unit = Unit::new().unwrap();
+///
+///     let api = Router::new()
+///         .get("/users/:id", |req: Request, params: &Params| {
+///             let body = format!("User {}\n", params.get("id").unwrap());
+///             req.send_response(200, &[("Content-Type", "text/plain")], body)
+///         })
+///         .delete("/users/:id", |req: Request, params: &Params| {
+///             let body = format!("Deleted user {}\n", params.get("id").unwrap());
+///             req.send_response(200, &[("Content-Type", "text/plain")], body)
+///         });
+///
+///     let router = Router::new()
+///         .get("/", |req: Request, _params: &Params| {
+///             req.send_response(200, &[("Content-Type", "text/plain")], "Home\n")
+///         })
+///         .get("/static/*file", |req: Request, params: &Params| {
+///             let body = format!("File {}\n", params.get("file").unwrap());
+///             req.send_response(200, &[("Content-Type", "text/plain")], body)
+///         })
+///         .mount("/api", api);
+///
+///     unit.set_request_handler(router);
+///     unit.run();
+/// }
+/// ```
+#[derive(Default)]
+pub struct Router {
+    entries: Vec<Entry>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a route for requests with the given method and path pattern.
+    ///
+    /// # Panics
+    /// Panics if a wildcard segment is not the last segment of the pattern.
+    pub fn route(self, method: &str, pattern: &str, handler: impl RouteHandler + 'static) -> Self {
+        self.add_route(Some(method.to_string()), pattern, handler)
+    }
+
+    /// Add a route for requests with any method and the given path pattern.
+    ///
+    /// # Panics
+    /// Panics if a wildcard segment is not the last segment of the pattern.
+    pub fn any(self, pattern: &str, handler: impl RouteHandler + 'static) -> Self {
+        self.add_route(None, pattern, handler)
+    }
+
+    /// Add a route for `GET` requests. See [`Router::route()`].
+    pub fn get(self, pattern: &str, handler: impl RouteHandler + 'static) -> Self {
+        self.route("GET", pattern, handler)
+    }
+
+    /// Add a route for `POST` requests. See [`Router::route()`].
+    pub fn post(self, pattern: &str, handler: impl RouteHandler + 'static) -> Self {
+        self.route("POST", pattern, handler)
+    }
+
+    /// Add a route for `PUT` requests. See [`Router::route()`].
+    pub fn put(self, pattern: &str, handler: impl RouteHandler + 'static) -> Self {
+        self.route("PUT", pattern, handler)
+    }
+
+    /// Add a route for `PATCH` requests. See [`Router::route()`].
+    pub fn patch(self, pattern: &str, handler: impl RouteHandler + 'static) -> Self {
+        self.route("PATCH", pattern, handler)
+    }
+
+    /// Add a route for `DELETE` requests. See [`Router::route()`].
+    pub fn delete(self, pattern: &str, handler: impl RouteHandler + 'static) -> Self {
+        self.route("DELETE", pattern, handler)
+    }
+
+    /// Mount another router at the given prefix.
+    ///
+    /// The prefix may contain parameters, which are passed to the nested
+    /// router's handlers as well. A request for the prefix itself is matched
+    /// against the nested router's `/` route.
+    ///
+    /// # Panics
+    /// Panics if the prefix contains a wildcard segment.
+    pub fn mount(mut self, prefix: &str, router: Router) -> Self {
+        let prefix = parse_pattern(prefix.trim_end_matches('/'));
+
+        assert!(
+            !prefix
+                .iter()
+                .any(|segment| matches!(segment, Segment::Wildcard(_))),
+            "Router prefixes cannot contain wildcards"
+        );
+
+        self.entries.push(Entry::Mount { prefix, router });
+        self
+    }
+
+    fn add_route(
+        mut self,
+        method: Option<String>,
+        pattern: &str,
+        handler: impl RouteHandler + 'static,
+    ) -> Self {
+        let pattern = parse_pattern(pattern);
+
+        if let Some(position) = pattern
+            .iter()
+            .position(|segment| matches!(segment, Segment::Wildcard(_)))
+        {
+            assert!(
+                position == pattern.len() - 1,
+                "Wildcards must be the last segment of a route"
+            );
+        }
+
+        self.entries.push(Entry::Route {
+            method,
+            pattern,
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    fn resolve(&self, method: &str, path: &[String]) -> Resolution {
+        let mut allowed_methods = Vec::new();
+
+        for (index, entry) in self.entries.iter().enumerate() {
+            match entry {
+                Entry::Route {
+                    method: route_method,
+                    pattern,
+                    ..
+                } => {
+                    let params = match match_route(pattern, path) {
+                        Some(params) => params,
+                        None => continue,
+                    };
+
+                    match route_method {
+                        Some(route_method) if route_method != method => {
+                            allowed_methods.push(route_method.clone());
+                        }
+                        _ => return Resolution::Found(vec![index], params),
+                    }
+                }
+                Entry::Mount { prefix, router } => {
+                    let (mut params, rest) = match match_prefix(prefix, path) {
+                        Some(matched) => matched,
+                        None => continue,
+                    };
+
+                    // The prefix itself is handled by the nested root route.
+                    let root = [String::new()];
+                    let rest = if rest.is_empty() { &root[..] } else { rest };
+
+                    match router.resolve(method, rest) {
+                        Resolution::Found(mut indexes, nested_params) => {
+                            indexes.insert(0, index);
+                            params.params.extend(nested_params.params);
+                            return Resolution::Found(indexes, params);
+                        }
+                        Resolution::MethodNotAllowed(methods) => allowed_methods.extend(methods),
+                        Resolution::NotFound => {}
+                    }
+                }
+            }
+        }
+
+        if allowed_methods.is_empty() {
+            Resolution::NotFound
+        } else {
+            allowed_methods.sort();
+            allowed_methods.dedup();
+            Resolution::MethodNotAllowed(allowed_methods)
+        }
+    }
+
+    fn resolve_request(&self, req: &Request) -> Resolution {
+        self.resolve(&req.method(), &split_path(req.target_bytes()))
+    }
+
+    fn handler_at(&mut self, indexes: &[usize]) -> &mut dyn RouteHandler {
+        match &mut self.entries[indexes[0]] {
+            Entry::Route { handler, .. } => handler.as_mut(),
+            Entry::Mount { router, .. } => router.handler_at(&indexes[1..]),
+        }
+    }
+}
+
+impl UnitService for Router {
+    fn handle_request(&mut self, req: Request) -> UnitResult<()> {
+        match self.resolve_request(&req) {
+            Resolution::Found(indexes, params) => {
+                self.handler_at(&indexes).handle_request(req, &params)
+            }
+            resolution => send_error(&req, resolution),
+        }
+    }
+
+    fn on_headers(&mut self, req: &Request) -> UnitResult<HeadersAction> {
+        match self.resolve_request(req) {
+            Resolution::Found(..) => Ok(HeadersAction::Buffer),
+            resolution => {
+                send_error(req, resolution)?;
+                Ok(HeadersAction::Finish)
+            }
+        }
+    }
+}
+
+fn send_error(req: &Request, resolution: Resolution) -> UnitResult<()> {
+    match resolution {
+        Resolution::MethodNotAllowed(methods) => {
+            let allow = methods.join(", ");
+            let headers = &[("Content-Type", "text/plain"), ("Allow", allow.as_str())];
+            req.send_response(405, headers, "Method Not Allowed\n")
+        }
+        _ => req.send_response(404, &[("Content-Type", "text/plain")], "Not Found\n"),
+    }
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    pattern
+        .trim_start_matches('/')
+        .trim_end_matches('/')
+        .split('/')
+        .map(|segment| {
+            if let Some(name) = segment.strip_prefix(':') {
+                Segment::Param(name.to_string())
+            } else if let Some(name) = segment.strip_prefix('*') {
+                Segment::Wildcard(name.to_string())
+            } else {
+                Segment::Literal(segment.to_string())
+            }
+        })
+        .collect()
+}
+
+/// Match a whole path against a route's pattern.
+fn match_route(pattern: &[Segment], path: &[String]) -> Option<Params> {
+    let mut params = Params::default();
+
+    for (index, segment) in pattern.iter().enumerate() {
+        match segment {
+            Segment::Wildcard(name) => {
+                let rest = path.get(index..).unwrap_or_default().join("/");
+                params.params.push((name.clone(), rest));
+                return Some(params);
+            }
+            Segment::Param(name) => {
+                let value = path.get(index).filter(|value| !value.is_empty())?;
+                params.params.push((name.clone(), value.clone()));
+            }
+            Segment::Literal(literal) => {
+                if path.get(index) != Some(literal) {
+                    return None;
+                }
+            }
+        }
+    }
+
+    if path.len() == pattern.len() {
+        Some(params)
+    } else {
+        None
+    }
+}
+
+/// Match the start of a path against a mount prefix, and return the rest of
+/// the path.
+fn match_prefix<'a>(prefix: &[Segment], path: &'a [String]) -> Option<(Params, &'a [String])> {
+    // A prefix of "/" matches all paths.
+    let prefix = match prefix {
+        [Segment::Literal(literal)] if literal.is_empty() => &[],
+        prefix => prefix,
+    };
+
+    if path.len() < prefix.len() {
+        return None;
+    }
+
+    let params = match_route(prefix, &path[..prefix.len()])?;
+
+    Some((params, &path[prefix.len()..]))
+}
+
+/// Split the path of a request target into percent-decoded segments, without
+/// its leading and trailing slashes.
+///
+/// `.` and `..` segments are resolved before decoding, so that encoded dots
+/// are kept as they are.
+fn split_path(target: &[u8]) -> Vec<String> {
+    let path = target
+        .split(|&byte| byte == b'?')
+        .next()
+        .unwrap_or_default();
+    let path = path.strip_prefix(b"/").unwrap_or(path);
+
+    let mut segments = Vec::new();
+    for segment in path.split(|&byte| byte == b'/') {
+        match segment {
+            b"." => {}
+            b".." => {
+                segments.pop();
+            }
+            segment => segments.push(percent_decode(segment)),
+        }
+    }
+
+    // A trailing slash is ignored, except for the root path.
+    if segments.len() > 1 && matches!(segments.last(), Some(segment) if segment.is_empty()) {
+        segments.pop();
+    }
+    if segments.is_empty() {
+        segments.push(String::new());
+    }
+
+    segments
+}
+
+/// Decode `%XX` sequences; invalid sequences are kept as they are, and invalid
+/// UTF-8 is replaced with `U+FFFD REPLACEMENT CHARACTER`.
+fn percent_decode(segment: &[u8]) -> String {
+    let hex = |byte: u8| (byte as char).to_digit(16).map(|digit| digit as u8);
+
+    let mut decoded = Vec::with_capacity(segment.len());
+    let mut i = 0;
+
+    while i < segment.len() {
+        if segment[i] == b'%' && i + 2 < segment.len() {
+            if let (Some(high), Some(low)) = (hex(segment[i + 1]), hex(segment[i + 2])) {
+                decoded.push(high << 4 | low);
+                i += 3;
+                continue;
+            }
+        }
+
+        decoded.push(segment[i]);
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ok(_req: Request, _params: &Params) -> UnitResult<()> {
+        Ok(())
+    }
+
+    fn resolve(router: &Router, method: &str, target: &str) -> Resolution {
+        router.resolve(method, &split_path(target.as_bytes()))
+    }
+
+    fn found(indexes: &[usize], params: &[(&str, &str)]) -> Resolution {
+        let params = params
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+
+        Resolution::Found(indexes.to_vec(), Params { params })
+    }
+
+    #[test]
+    fn literal() {
+        let router = Router::new().get("/", ok).get("/users", ok);
+
+        assert_eq!(resolve(&router, "GET", "/"), found(&[0], &[]));
+        assert_eq!(resolve(&router, "GET", "/users"), found(&[1], &[]));
+        assert_eq!(resolve(&router, "GET", "/users/"), found(&[1], &[]));
+        assert_eq!(resolve(&router, "GET", "/users?page=2"), found(&[1], &[]));
+        assert_eq!(resolve(&router, "GET", "/other"), Resolution::NotFound);
+        assert_eq!(resolve(&router, "GET", "/users/1"), Resolution::NotFound);
+    }
+
+    #[test]
+    fn param() {
+        let router = Router::new().get("/users/:id", ok);
+
+        assert_eq!(
+            resolve(&router, "GET", "/users/1"),
+            found(&[0], &[("id", "1")])
+        );
+        assert_eq!(
+            resolve(&router, "GET", "/users/1/"),
+            found(&[0], &[("id", "1")])
+        );
+        assert_eq!(
+            resolve(&router, "GET", "/users/a%20b"),
+            found(&[0], &[("id", "a b")])
+        );
+        assert_eq!(
+            resolve(&router, "GET", "/users/a%2Fb"),
+            found(&[0], &[("id", "a/b")])
+        );
+        assert_eq!(resolve(&router, "GET", "/users/"), Resolution::NotFound);
+        assert_eq!(resolve(&router, "GET", "/users"), Resolution::NotFound);
+    }
+
+    #[test]
+    fn wildcard() {
+        let router = Router::new().get("/static/*file", ok);
+
+        assert_eq!(
+            resolve(&router, "GET", "/static/css/site.css"),
+            found(&[0], &[("file", "css/site.css")])
+        );
+        assert_eq!(
+            resolve(&router, "GET", "/static"),
+            found(&[0], &[("file", "")])
+        );
+        assert_eq!(
+            resolve(&router, "GET", "/static/"),
+            found(&[0], &[("file", "")])
+        );
+        assert_eq!(resolve(&router, "GET", "/other/file"), Resolution::NotFound);
+    }
+
+    #[test]
+    fn dot_segments() {
+        let router = Router::new().get("/users/:id", ok);
+
+        assert_eq!(
+            resolve(&router, "GET", "/static/../users/./1"),
+            found(&[0], &[("id", "1")])
+        );
+        assert_eq!(
+            resolve(&router, "GET", "/users/%2E%2E"),
+            found(&[0], &[("id", "..")])
+        );
+    }
+
+    #[test]
+    fn mount_at_root() {
+        let router = Router::new().mount("/", Router::new().get("/", ok).get("/about", ok));
+
+        assert_eq!(resolve(&router, "GET", "/"), found(&[0, 0], &[]));
+        assert_eq!(resolve(&router, "GET", "/about"), found(&[0, 1], &[]));
+        assert_eq!(resolve(&router, "GET", "/other"), Resolution::NotFound);
+    }
+
+    #[test]
+    fn mount_at_prefix() {
+        let api = Router::new().get("/", ok).get("/users/:id", ok);
+        let router = Router::new().get("/", ok).mount("/api/:version/", api);
+
+        assert_eq!(resolve(&router, "GET", "/"), found(&[0], &[]));
+        assert_eq!(
+            resolve(&router, "GET", "/api/v1"),
+            found(&[1, 0], &[("version", "v1")])
+        );
+        assert_eq!(
+            resolve(&router, "GET", "/api/v1/"),
+            found(&[1, 0], &[("version", "v1")])
+        );
+        assert_eq!(
+            resolve(&router, "GET", "/api/v1/users/7"),
+            found(&[1, 1], &[("version", "v1"), ("id", "7")])
+        );
+        assert_eq!(resolve(&router, "GET", "/api"), Resolution::NotFound);
+        assert_eq!(
+            resolve(&router, "GET", "/api/v1/other"),
+            Resolution::NotFound
+        );
+    }
+
+    #[test]
+    fn method_not_allowed() {
+        let router = Router::new()
+            .post("/items", ok)
+            .get("/items", ok)
+            .mount("/", Router::new().get("/items", ok).put("/items", ok))
+            .delete("/other", ok);
+
+        assert_eq!(resolve(&router, "GET", "/items"), found(&[1], &[]));
+        assert_eq!(resolve(&router, "PUT", "/items"), found(&[2, 1], &[]));
+        assert_eq!(
+            resolve(&router, "DELETE", "/items"),
+            Resolution::MethodNotAllowed(vec![
+                "GET".to_string(),
+                "POST".to_string(),
+                "PUT".to_string()
+            ])
+        );
+        assert_eq!(resolve(&router, "GET", "/missing"), Resolution::NotFound);
+    }
+
+    #[test]
+    fn any_method() {
+        let router = Router::new().get("/items", ok).any("/items", ok);
+
+        assert_eq!(resolve(&router, "GET", "/items"), found(&[0], &[]));
+        assert_eq!(resolve(&router, "PATCH", "/items"), found(&[1], &[]));
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let router = Router::new().get("/users/me", ok).get("/users/:id", ok);
+
+        assert_eq!(resolve(&router, "GET", "/users/me"), found(&[0], &[]));
+        assert_eq!(
+            resolve(&router, "GET", "/users/5"),
+            found(&[1], &[("id", "5")])
+        );
+
+        let router = Router::new().get("/users/:id", ok).get("/users/me", ok);
+
+        assert_eq!(
+            resolve(&router, "GET", "/users/me"),
+            found(&[0], &[("id", "me")])
+        );
+    }
+
+    #[test]
+    fn parse_pattern_segments() {
+        let pattern = parse_pattern("/users/:id/*rest/");
+
+        assert!(matches!(&pattern[0], Segment::Literal(literal) if literal == "users"));
+        assert!(matches!(&pattern[1], Segment::Param(name) if name == "id"));
+        assert!(matches!(&pattern[2], Segment::Wildcard(name) if name == "rest"));
+        assert_eq!(pattern.len(), 3);
+    }
+
+    #[test]
+    #[should_panic]
+    fn wildcard_must_be_last() {
+        let _ = Router::new().get("/static/*file/edit", ok);
+    }
+}
@@ -0,0 +1,92 @@
+use std::sync::Arc;
+
+use crate::error::UnitResult;
+use crate::request::Request;
+use crate::unit::{HeadersAction, UnitService};
+
+/// A trait for request handlers that can be shared between all Unit
+/// contexts, and therefore between threads.
+///
+/// Unlike [`UnitService`], the methods of this trait take `&self`; state that
+/// changes must use interior mutability, such as atomics or a
+/// [`Mutex`](std::sync::Mutex).
+///
+/// Handlers that implement this trait can be installed on a context with
+/// [`Unit::set_shared_handler()`](crate::Unit::set_shared_handler), or
+/// converted to a [`UnitService`] with the [`SharedHandler`] adapter.
+///
+/// This trait is automatically implemented for functions or lambda functions
+/// that take a [`Request`] object and return a [`UnitResult<()>`](UnitResult),
+/// and that are [`Send`] and [`Sync`].
+pub trait SharedUnitService: Send + Sync {
+    fn handle_request(&self, req: Request) -> UnitResult<()>;
+
+    /// See [`UnitService::on_headers()`].
+    fn on_headers(&self, _req: &Request) -> UnitResult<HeadersAction> {
+        Ok(HeadersAction::Buffer)
+    }
+
+    /// See [`UnitService::on_body_data()`].
+    fn on_body_data(&self, _req: &Request) -> UnitResult<()> {
+        Ok(())
+    }
+}
+
+impl<F> SharedUnitService for F
+where
+    F: Fn(Request) -> UnitResult<()> + Send + Sync + 'static,
+{
+    fn handle_request(&self, req: Request) -> UnitResult<()> {
+        self(req)
+    }
+}
+
+/// Adapter to use a [`SharedUnitService`] as a [`UnitService`].
+///
+/// Each context gets its own adapter, but all of them call the same handler.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::sync::atomic::{AtomicU64, Ordering};
+/// use std::sync::Arc;
+///
+/// use unit_rs::{Request, SharedHandler, SharedUnitService, Unit};
+///
+/// fn main() {
+///     let visits = AtomicU64::new(0);
+///
+///     let handler: Arc<dyn SharedUnitService> = Arc::new(move |req: Request| {
+///         let visits = visits.fetch_add(1, Ordering::Relaxed) + 1;
+///         let body = format!("Visits on all threads: {}\n", visits);
+///         req.send_response(200, &[("Content-Type", "text/plain")], body)
+///     });
+///
+///     let mut unit = Unit::new().unwrap();
+///
+///     unit.run_threads(None, || SharedHandler::new(handler.clone()))
+///         .unwrap();
+/// }
+/// ```
+#[derive(Clone)]
+pub struct SharedHandler(Arc<dyn SharedUnitService>);
+
+impl SharedHandler {
+    pub fn new(shared_service: Arc<dyn SharedUnitService>) -> Self {
+        Self(shared_service)
+    }
+}
+
+impl UnitService for SharedHandler {
+    fn handle_request(&mut self, req: Request) -> UnitResult<()> {
+        self.0.handle_request(req)
+    }
+
+    fn on_headers(&mut self, req: &Request) -> UnitResult<HeadersAction> {
+        self.0.on_headers(req)
+    }
+
+    fn on_body_data(&mut self, req: &Request) -> UnitResult<()> {
+        self.0.on_body_data(req)
+    }
+}
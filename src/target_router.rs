@@ -0,0 +1,134 @@
+use crate::error::UnitResult;
+use crate::request::{LogLevel, Request};
+use crate::unit::{HeadersAction, UnitService};
+
+/// A request handler that dispatches requests to other handlers, based on the
+/// application target that Unit routed them to.
+///
+/// Unit's configuration can define several `targets` for one application,
+/// and route requests to them with `"pass": "applications/<app>/<target>"`.
+/// This allows one binary to serve several logical applications.
+///
+/// libunit only reports the index of the chosen target (see
+/// [`Request::app_target()`]). The `NXT_UNIT_INIT` environment variable with
+/// which Unit starts the application carries its ports and limits, but not
+/// the names of its targets, so the names cannot be checked against Unit's
+/// configuration. Targets must therefore be added in the same order as in the
+/// `targets` object of the application's configuration, and their names are
+/// only used for logging and lookups.
+///
+/// Requests for targets without a handler receive a 404 response, unless a
+/// fallback handler is set with [`TargetRouter::fallback()`]. Since this
+/// usually means that the targets were not added in the same order as in
+/// Unit's configuration, the first such request is logged as a warning.
+///
+/// # Example
+///
+/// ```no_run
+/// use unit_rs::{Request, TargetRouter, Unit};
+///
+/// fn main() {
+///     let mut unit = Unit::new().unwrap();
+///
+///     // Unit configuration:
+///     // "targets": { "front": { ... }, "admin": { ... } }
+///     let router = TargetRouter::new()
+///         .target("front", |req: Request| {
+///             req.send_response(200, &[("Content-Type", "text/plain")], "Front\n")
+///         })
+///         .target("admin", |req: Request| {
+///             req.send_response(200, &[("Content-Type", "text/plain")], "Admin\n")
+///         });
+///
+///     unit.set_request_handler(router);
+///     unit.run();
+/// }
+/// ```
+#[derive(Default)]
+pub struct TargetRouter {
+    targets: Vec<(String, Box<dyn UnitService>)>,
+    fallback: Option<Box<dyn UnitService>>,
+    warned_unknown_target: bool,
+}
+
+impl TargetRouter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the handler for the next target in the application's `targets`
+    /// configuration.
+    pub fn target(mut self, name: &str, service: impl UnitService + 'static) -> Self {
+        self.targets.push((name.to_string(), Box::new(service)));
+        self
+    }
+
+    /// Set the handler for requests to targets that were not added with
+    /// [`TargetRouter::target()`].
+    pub fn fallback(mut self, service: impl UnitService + 'static) -> Self {
+        self.fallback = Some(Box::new(service));
+        self
+    }
+
+    /// Return the name of the target with the given index, as reported by
+    /// [`Request::app_target()`].
+    pub fn target_name(&self, index: u8) -> Option<&str> {
+        self.targets
+            .get(index as usize)
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Return the index of the target with the given name.
+    pub fn target_index(&self, name: &str) -> Option<u8> {
+        self.targets
+            .iter()
+            .position(|(target_name, _)| target_name == name)
+            .and_then(|index| u8::try_from(index).ok())
+    }
+
+    fn service_for(&mut self, req: &Request) -> Option<&mut Box<dyn UnitService>> {
+        let app_target = req.app_target();
+
+        if app_target as usize >= self.targets.len() && !self.warned_unknown_target {
+            self.warned_unknown_target = true;
+            req.log(
+                LogLevel::Warning,
+                format!(
+                    "Request for application target {}, but only {} targets were added to \
+                     the TargetRouter; targets must be added in the same order as in Unit's \
+                     configuration",
+                    app_target,
+                    self.targets.len()
+                ),
+            );
+        }
+
+        match self.targets.get_mut(app_target as usize) {
+            Some((_, service)) => Some(service),
+            None => self.fallback.as_mut(),
+        }
+    }
+}
+
+impl UnitService for TargetRouter {
+    fn handle_request(&mut self, req: Request) -> UnitResult<()> {
+        match self.service_for(&req) {
+            Some(service) => service.handle_request(req),
+            None => req.send_response(404, &[("Content-Type", "text/plain")], "Not Found\n"),
+        }
+    }
+
+    fn on_headers(&mut self, req: &Request) -> UnitResult<HeadersAction> {
+        match self.service_for(req) {
+            Some(service) => service.on_headers(req),
+            None => Ok(HeadersAction::Buffer),
+        }
+    }
+
+    fn on_body_data(&mut self, req: &Request) -> UnitResult<()> {
+        match self.service_for(req) {
+            Some(service) => service.on_body_data(req),
+            None => Ok(()),
+        }
+    }
+}
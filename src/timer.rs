@@ -0,0 +1,149 @@
+use std::cell::Cell;
+use std::panic::AssertUnwindSafe;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::panic;
+use crate::unit::ContextData;
+
+pub(crate) enum TimerCallback {
+    Once(Box<dyn FnOnce()>),
+    Interval(Box<dyn FnMut()>, Duration),
+}
+
+/// A callback scheduled on a context's event loop.
+pub(crate) struct Timer {
+    deadline: Instant,
+    callback: TimerCallback,
+    cancelled: Rc<Cell<bool>>,
+}
+
+/// A handle to a callback scheduled with
+/// [`Unit::schedule_once()`](crate::Unit::schedule_once) or
+/// [`Unit::schedule_interval()`](crate::Unit::schedule_interval).
+///
+/// Dropping this handle does not cancel the callback. The handle is not
+/// [`Send`] nor [`Sync`], but it can be moved into request handlers or other
+/// callbacks of the same context.
+#[derive(Debug, Clone)]
+pub struct TimerHandle {
+    cancelled: Rc<Cell<bool>>,
+}
+
+impl TimerHandle {
+    pub(crate) fn cancelled() -> Self {
+        TimerHandle {
+            cancelled: Rc::new(Cell::new(true)),
+        }
+    }
+
+    /// Cancel the callback; it will not be called again.
+    pub fn cancel(&self) {
+        self.cancelled.set(true);
+    }
+
+    /// Return whether or not the callback was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.get()
+    }
+}
+
+/// Add a timer to the context.
+///
+/// # Safety
+/// Must be called on the context's thread, with valid context data.
+pub(crate) unsafe fn schedule(
+    context_data: *mut ContextData,
+    delay: Duration,
+    callback: TimerCallback,
+) -> TimerHandle {
+    let cancelled = Rc::new(Cell::new(false));
+
+    // Timers cancelled before becoming due are otherwise only removed when
+    // other timers run.
+    (*context_data)
+        .timers
+        .retain(|timer| !timer.cancelled.get());
+
+    (*context_data).timers.push(Timer {
+        deadline: Instant::now() + delay,
+        callback,
+        cancelled: cancelled.clone(),
+    });
+
+    TimerHandle { cancelled }
+}
+
+/// Return when the next timer is due, if any.
+///
+/// # Safety
+/// Must be called on the context's thread, with valid context data.
+pub(crate) unsafe fn next_deadline(context_data: *mut ContextData) -> Option<Instant> {
+    (*context_data)
+        .timers
+        .iter()
+        .filter(|timer| !timer.cancelled.get())
+        .map(|timer| timer.deadline)
+        .min()
+}
+
+/// Call the callbacks of all timers that are due, and reschedule intervals.
+///
+/// # Safety
+/// Must be called on the context's own thread, while no references to the
+/// context's data are alive.
+pub(crate) unsafe fn run_due_timers(context_data: *mut ContextData) {
+    let now = Instant::now();
+
+    // Due timers are taken out of the context data while their callbacks run,
+    // since the callbacks may access the context data as well.
+    let timers = std::mem::take(&mut (*context_data).timers);
+    let (due, pending): (Vec<_>, Vec<_>) = timers
+        .into_iter()
+        .filter(|timer| !timer.cancelled.get())
+        .partition(|timer| timer.deadline <= now);
+
+    (*context_data).timers.extend(pending);
+
+    for timer in due {
+        // Earlier callbacks may have cancelled this one.
+        if timer.cancelled.get() {
+            continue;
+        }
+
+        let Timer {
+            deadline,
+            callback,
+            cancelled,
+        } = timer;
+
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| match callback {
+            TimerCallback::Once(f) => {
+                f();
+                None
+            }
+            TimerCallback::Interval(mut f, interval) => {
+                f();
+                Some((f, interval))
+            }
+        }));
+
+        match result {
+            Ok(Some((f, interval))) if !cancelled.get() => {
+                // Missed ticks are skipped instead of running all at once.
+                let mut deadline = deadline + interval;
+                if deadline <= now {
+                    deadline = now + interval;
+                }
+
+                (*context_data).timers.push(Timer {
+                    deadline,
+                    callback: TimerCallback::Interval(f, interval),
+                    cancelled,
+                });
+            }
+            Ok(_) => {}
+            Err(panic_payload) => panic::handle_panic(context_data, "Timer", panic_payload),
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::future::{poll_fn, Future};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::task::Poll;
+use std::time::Duration;
+
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+
+use crate::event_loop;
+use crate::nxt_unit::{nxt_unit_ctx_t, nxt_unit_port_t};
+use crate::timer;
+use crate::unit::ContextData;
+
+// A duplicate of a file descriptor owned by Unit.
+//
+// Unit's shared port is read by all contexts, which may be registered with the
+// same reactor; duplicating the file descriptor allows each context to register
+// it separately. It also keeps the registration valid if Unit closes the
+// original file descriptor before the port is removed from the reactor.
+struct DupFd(RawFd);
+
+impl DupFd {
+    fn new(fd: RawFd) -> std::io::Result<Self> {
+        // SAFETY: The new file descriptor is owned by this object, and closed
+        // when it is dropped.
+        let fd = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+
+        if fd == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(DupFd(fd))
+    }
+}
+
+impl AsRawFd for DupFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl Drop for DupFd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
+
+fn register(fd: RawFd) -> std::io::Result<AsyncFd<DupFd>> {
+    AsyncFd::with_interest(DupFd::new(fd)?, Interest::READABLE)
+}
+
+/// Process the context's ports and mailbox whenever tokio's reactor reports
+/// them as readable, until the context is asked to quit.
+///
+/// Ports added or removed by Unit through the `add_port` and `remove_port`
+/// callbacks are registered with or removed from the reactor before waiting
+/// for new events.
+///
+/// # Safety
+/// Must be called on the context's own thread, while no references to the
+/// context's data are alive. The returned future must not outlive the context.
+pub(crate) async unsafe fn run_context(ctx: *mut nxt_unit_ctx_t) -> std::io::Result<()> {
+    run_events(ctx, false).await
+}
+
+/// Keep processing the context's events after it was asked to quit, until it
+/// has no more work in flight or the drain period ends.
+///
+/// # Safety
+/// Same as [`run_context()`].
+pub(crate) async unsafe fn drain_context(
+    ctx: *mut nxt_unit_ctx_t,
+    drain_period: Duration,
+) -> std::io::Result<()> {
+    let context_data = (*ctx).data as *mut ContextData;
+
+    // An error while draining sets the flag again, and stops the loop.
+    (*context_data).quit = false;
+    let result = tokio::time::timeout(drain_period, run_events(ctx, true)).await;
+    (*context_data).quit = true;
+
+    result.unwrap_or(Ok(()))
+}
+
+async unsafe fn run_events(ctx: *mut nxt_unit_ctx_t, drain: bool) -> std::io::Result<()> {
+    let context_data = (*ctx).data as *mut ContextData;
+
+    let mailbox_fd = register((*context_data).mailbox.read_fd())?;
+    let mut port_fds: HashMap<(*mut nxt_unit_port_t, RawFd), AsyncFd<DupFd>> = HashMap::new();
+
+    // The context data is modified by Unit's callbacks.
+    loop {
+        if (*context_data).quit || (drain && !event_loop::has_work_in_flight(context_data)) {
+            return Ok(());
+        }
+
+        let ports = event_loop::context_ports(context_data);
+
+        // Removed ports may already be deallocated, so only the current ports
+        // are dereferenced.
+        let current: Vec<_> = ports.iter().map(|port| (*port, (**port).in_fd)).collect();
+        port_fds.retain(|key, _| current.contains(key));
+
+        for key in current {
+            if let Entry::Vacant(entry) = port_fds.entry(key) {
+                entry.insert(register(key.1)?);
+            }
+        }
+
+        // Wake up in time for the next timer.
+        let mut timer_sleep = timer::next_deadline(context_data)
+            .map(|deadline| Box::pin(tokio::time::sleep_until(deadline.into())));
+
+        let (mailbox_ready, ready_ports) = poll_fn(|cx| {
+            let mut mailbox_ready = false;
+            let mut ready_ports = Vec::new();
+
+            // Readiness is cleared before processing, which reads until the
+            // file descriptors have no more data.
+            if let Poll::Ready(guard) = mailbox_fd.poll_read_ready(cx) {
+                guard?.clear_ready();
+                mailbox_ready = true;
+            }
+
+            for ((port, _), port_fd) in &port_fds {
+                if let Poll::Ready(guard) = port_fd.poll_read_ready(cx) {
+                    guard?.clear_ready();
+                    ready_ports.push(*port);
+                }
+            }
+
+            let timer_ready = match &mut timer_sleep {
+                Some(sleep) => sleep.as_mut().poll(cx).is_ready(),
+                None => false,
+            };
+
+            if mailbox_ready || !ready_ports.is_empty() || timer_ready {
+                Poll::Ready(Ok::<_, std::io::Error>((mailbox_ready, ready_ports)))
+            } else {
+                Poll::Pending
+            }
+        })
+        .await?;
+
+        if mailbox_ready {
+            event_loop::run_mailbox(context_data);
+        }
+
+        for port in ready_ports {
+            event_loop::process_port(ctx, port);
+        }
+
+        timer::run_due_timers(context_data);
+    }
+}
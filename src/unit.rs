@@ -1,22 +1,55 @@
 use std::any::Any;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::os::unix::io::RawFd;
 use std::panic::AssertUnwindSafe;
 use std::ptr::NonNull;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex, MutexGuard, Once, Weak};
+use std::task::Waker;
+use std::time::{Duration, Instant};
 
-use libc::c_void;
+use libc::{c_char, c_void};
 
-use crate::error::{UnitError, UnitInitError, UnitResult};
+use crate::builder::UnitBuilder;
+use crate::detached::{DetachedRequest, DetachedState};
+use crate::error::{UnitBuildError, UnitError, UnitInitError, UnitResult};
+use crate::event_loop::{self, ContextMailbox, EventSources};
+use crate::executor::{AsyncHandler, AsyncUnitService, LocalTask, TaskWaker};
 use crate::nxt_unit::{
-    self, nxt_unit_ctx_t, nxt_unit_done, nxt_unit_init, nxt_unit_init_t, nxt_unit_request_done,
-    nxt_unit_request_info_t, nxt_unit_response_init, nxt_unit_run,
+    self, nxt_unit_ctx_t, nxt_unit_done, nxt_unit_init, nxt_unit_init_t, nxt_unit_port_t,
+    nxt_unit_request_done, nxt_unit_request_info_t, nxt_unit_response_init,
 };
+use crate::panic::{self, PanicPolicy};
+use crate::queue::QueuedRequest;
 use crate::request::Request;
+use crate::shared::{SharedHandler, SharedUnitService};
+use crate::timer::{self, Timer, TimerCallback, TimerHandle};
+#[cfg(feature = "tokio")]
+use crate::tokio_loop;
+use crate::websocket::{self, HubSocket, WebSocketState};
 
 unsafe extern "C" fn request_handler(req: *mut nxt_unit_request_info_t) {
+    // Repeated headers are grouped together, for Request::headers_all(). This
+    // reorders the fields (see Request::fields()), and leaves
+    // authorization_field stale.
+    nxt_unit::nxt_unit_request_group_dup_fields(req);
+
+    handle_new_request(req);
+}
+
+// Handle a request whose fields were already grouped. Queued requests are
+// grouped when they are dequeued, and dispatched through this function.
+pub(crate) unsafe fn handle_new_request(req: *mut nxt_unit_request_info_t) {
     // SAFETY: The context data is passed as Unit context-specific user data,
     // and individual Unit contexts correspond to individual threads.
     let context_data = (*(*req).ctx).data as *mut ContextData;
-    let context_data = &mut *context_data;
+
+    if context_data.is_null() {
+        nxt_unit_request_done(req, nxt_unit::NXT_UNIT_ERROR as i32);
+        return;
+    }
 
     let rc = nxt_unit_response_init(req, 200, 1, 0 as u32);
 
@@ -25,41 +58,396 @@ unsafe extern "C" fn request_handler(req: *mut nxt_unit_request_info_t) {
         return;
     }
 
-    let rc = if let Some(service) = &mut context_data.request_handler {
-        let unit_request = Request {
-            nxt_request: &mut *req,
-            _lifetime: Default::default(),
-        };
+    // Since a data handler is set, Unit calls the request handler as soon as
+    // the headers arrive, even if the body is not yet fully buffered.
+    let action = call_service(
+        context_data,
+        req,
+        Ok(HeadersAction::Buffer),
+        |service, req| service.on_headers(&req),
+    );
+
+    let action = match action {
+        Some(action) => action,
+        None => return,
+    };
+
+    match action {
+        Ok(HeadersAction::Finish) => finish_request(context_data, req, Ok(())),
+        Ok(action) => {
+            // A request detached by the service is owned by its handle, and
+            // may even have been finished already.
+            if (*context_data).request_detached {
+                if (*context_data).detached_requests.contains_key(&req) && !body_is_complete(req) {
+                    (*context_data).pending_bodies.insert(req, action);
+                }
+                return;
+            }
+
+            if action == HeadersAction::Stream && body_buffered_len(req) > 0 {
+                match call_service(context_data, req, Ok(()), |service, req| {
+                    service.on_body_data(&req)
+                }) {
+                    Some(Ok(())) => {}
+                    Some(Err(err)) => {
+                        finish_request(context_data, req, Err(err));
+                        return;
+                    }
+                    None => return,
+                }
+            }
 
-        // This assertion is safe because the panic payload is not examined, and
-        // the panic will just be forwarded through Unit's C FFI and resumed.
-        let handler = AssertUnwindSafe(|| service.handle_request(unit_request));
-
-        match std::panic::catch_unwind(handler) {
-            Ok(Ok(())) => nxt_unit::NXT_UNIT_OK as i32,
-            Ok(Err(UnitError(rc))) => rc,
-            Err(panic_payload) => {
-                nxt_unit_request_done(req, nxt_unit::NXT_UNIT_ERROR as i32);
-
-                // FIXME: Find a way to stop the run loop
-                // Trying to implement `nxt_unit_run` manually is not possible
-                // since `nxt_unit_quit` is not exposed in the API.
-                std::panic::resume_unwind(panic_payload)
-                // context_data.panic_payload = Some(panic_payload);
-                // nxt_unit::NXT_UNIT_ERROR as i32
+            if body_is_complete(req) {
+                handle_request(context_data, req);
+            } else {
+                (*context_data).pending_bodies.insert(req, action);
             }
         }
-    } else {
-        nxt_unit::NXT_UNIT_OK as i32
+        Err(err) => finish_request(context_data, req, Err(err)),
+    }
+}
+
+unsafe extern "C" fn data_handler(req: *mut nxt_unit_request_info_t) {
+    let context_data = (*(*req).ctx).data as *mut ContextData;
+
+    if context_data.is_null() {
+        return;
+    }
+
+    let action = match (*context_data).pending_bodies.get(&req) {
+        Some(action) => *action,
+        None => return,
     };
 
+    // Detached requests read their body through their own handle.
+    if (*context_data).detached_requests.contains_key(&req) {
+        if let Some(waker) = (*context_data).body_wakers.remove(&req) {
+            waker.wake();
+        }
+
+        if body_is_complete(req) {
+            (*context_data).pending_bodies.remove(&req);
+        }
+        return;
+    }
+
+    if action == HeadersAction::Stream {
+        match call_service(context_data, req, Ok(()), |service, req| {
+            service.on_body_data(&req)
+        }) {
+            Some(Ok(())) => {}
+            Some(Err(err)) => {
+                (*context_data).pending_bodies.remove(&req);
+                finish_request(context_data, req, Err(err));
+                return;
+            }
+            None => return,
+        }
+    }
+
+    if body_is_complete(req) {
+        (*context_data).pending_bodies.remove(&req);
+        handle_request(context_data, req);
+    }
+}
+
+/// Return the number of request body bytes that were received but not read
+/// yet.
+unsafe fn body_buffered_len(req: *mut nxt_unit_request_info_t) -> u64 {
+    let mut len = 0;
+    let mut buf = (*req).content_buf;
+
+    while !buf.is_null() {
+        len += (*buf).end.offset_from((*buf).free) as u64;
+        buf = nxt_unit::nxt_unit_buf_next(buf);
+    }
+
+    len
+}
+
+/// Return whether or not the rest of the request body was received.
+unsafe fn body_is_complete(req: *mut nxt_unit_request_info_t) -> bool {
+    // Bodies stored by Unit in a file are always complete.
+    (*req).content_fd != -1 || body_buffered_len(req) >= (*req).content_length
+}
+
+/// Call the request handler's service, with the service taken out of the
+/// context data while it runs.
+///
+/// If no service was set, the default value is returned instead.
+///
+/// Returns `None` if the service panicked, in which case the request was
+/// already finished, and the panic handled according to the context's
+/// [`PanicPolicy`].
+unsafe fn call_service<T>(
+    context_data: *mut ContextData,
+    req: *mut nxt_unit_request_info_t,
+    default: T,
+    f: impl FnOnce(&mut dyn UnitService, Request) -> T,
+) -> Option<T> {
+    (*context_data).request_detached = false;
+
+    // The service is taken out of the context data while it runs, since the
+    // request may need to store its own state in the context data (e.g. when
+    // upgrading to a WebSocket).
+    let mut service = match (*context_data).request_handler.take() {
+        Some(service) => service,
+        None => return Some(default),
+    };
+
+    let unit_request = Request {
+        nxt_request: &mut *req,
+        _lifetime: Default::default(),
+    };
+
+    // This assertion is safe because the panic payload is not examined, and
+    // the panic will either be resumed once the event loop stops, or the
+    // service will be kept only if the panic policy allows it.
+    let handler = AssertUnwindSafe(|| f(service.as_mut(), unit_request));
+    let result = std::panic::catch_unwind(handler);
+
+    (*context_data).request_handler = Some(service);
+
+    let panic_payload = match result {
+        Ok(result) => return Some(result),
+        Err(panic_payload) => panic_payload,
+    };
+
+    (*context_data).pending_bodies.remove(&req);
+
+    // Detached requests are finished by their own handle, which may already
+    // have been dropped while unwinding.
+    let detached = (*context_data).request_detached;
+
+    if !detached {
+        let rc = match (*context_data).panic_policy {
+            PanicPolicy::Respond500AndContinue => respond_500(req),
+            _ => nxt_unit::NXT_UNIT_ERROR as i32,
+        };
+
+        if let Some(state) = (*context_data).websockets.remove(&req) {
+            websocket::close_socket(context_data, state.socket());
+        }
+
+        nxt_unit_request_done(req, rc);
+    }
+
+    // The panic must not unwind through Unit's C frames; it is stored and
+    // resumed once the event loop stops, unless the policy says otherwise.
+    panic::handle_panic(context_data, "Request handler", panic_payload);
+
+    None
+}
+
+/// Respond with a 500 error, unless a response was already sent.
+///
+/// Returns the code with which the request should be finished.
+unsafe fn respond_500(req: *mut nxt_unit_request_info_t) -> i32 {
+    // Nothing can be changed once the headers were sent; a response that was
+    // only initialized is replaced.
+    if nxt_unit::nxt_unit_response_is_init(req) != 0
+        && nxt_unit::nxt_unit_response_is_sent(req) != 0
+    {
+        return nxt_unit::NXT_UNIT_ERROR as i32;
+    }
+
+    let content_type = ("Content-Type", "text/plain");
+    let body = "Internal Server Error\n";
+
+    let rc = nxt_unit_response_init(
+        req,
+        500,
+        1,
+        (content_type.0.len() + content_type.1.len() + body.len()) as u32,
+    );
+
+    if rc != nxt_unit::NXT_UNIT_OK as i32 {
+        return rc;
+    }
+
+    let rc = nxt_unit::nxt_unit_response_add_field(
+        req,
+        content_type.0.as_ptr() as *const c_char,
+        content_type.0.len() as u8,
+        content_type.1.as_ptr() as *const c_char,
+        content_type.1.len() as u32,
+    );
+
+    if rc != nxt_unit::NXT_UNIT_OK as i32 {
+        return rc;
+    }
+
+    let rc = nxt_unit::nxt_unit_response_add_content(
+        req,
+        body.as_ptr() as *const c_void,
+        body.len() as u32,
+    );
+
+    if rc != nxt_unit::NXT_UNIT_OK as i32 {
+        return rc;
+    }
+
+    nxt_unit::nxt_unit_response_send(req)
+}
+
+unsafe fn handle_request(context_data: *mut ContextData, req: *mut nxt_unit_request_info_t) {
+    let result = call_service(context_data, req, Ok(()), |service, req| {
+        service.handle_request(req)
+    });
+
+    if let Some(result) = result {
+        finish_request(context_data, req, result);
+    }
+}
+
+unsafe fn finish_request(
+    context_data: *mut ContextData,
+    req: *mut nxt_unit_request_info_t,
+    result: UnitResult<()>,
+) {
+    let rc = match result {
+        Ok(()) => nxt_unit::NXT_UNIT_OK as i32,
+        Err(UnitError(rc)) => rc,
+    };
+
+    // Detached requests are finished later, by their own handle, and may even
+    // have been finished already.
+    if (*context_data).request_detached {
+        return;
+    }
+
+    // Upgraded WebSocket requests stay alive until the connection is closed.
+    if rc == nxt_unit::NXT_UNIT_OK as i32 && (*context_data).websockets.contains_key(&req) {
+        return;
+    }
+
+    if let Some(state) = (*context_data).websockets.remove(&req) {
+        websocket::close_socket(context_data, state.socket());
+    }
+
     nxt_unit_request_done(req, rc);
 }
 
-struct ContextData {
+unsafe extern "C" fn close_handler(req: *mut nxt_unit_request_info_t) {
+    // Setting this callback means that Unit will no longer finish requests by
+    // itself when they are closed.
+    let context_data = (*(*req).ctx).data as *mut ContextData;
+
+    if context_data.is_null() {
+        nxt_unit_request_done(req, nxt_unit::NXT_UNIT_ERROR as i32);
+        return;
+    }
+
+    if websocket::handle_close(req) {
+        nxt_unit_request_done(req, nxt_unit::NXT_UNIT_OK as i32);
+        return;
+    }
+
+    (*context_data).pending_bodies.remove(&req);
+
+    // Readers waiting for more body data will see that the request is closed.
+    if let Some(waker) = (*context_data).body_wakers.remove(&req) {
+        waker.wake();
+    }
+
+    // Detached requests are finished by their own handle, since their
+    // request and response objects may still be in use.
+    if let Some(state) = (*context_data).detached_requests.get(&req) {
+        state.mark_closed();
+        return;
+    }
+
+    // Queued requests were not handled yet, and are simply forgotten.
+    if let Some(finished) = (*context_data).queued_requests.remove(&req) {
+        finished.set(true);
+    }
+
+    nxt_unit_request_done(req, nxt_unit::NXT_UNIT_ERROR as i32);
+}
+
+pub(crate) struct ContextData {
     request_handler: Option<Box<dyn UnitService>>,
     unit_is_ready: bool,
-    panic_payload: Option<Box<dyn Any + Send>>,
+    // A panic caught in a callback, resumed once the event loop stops.
+    pub(crate) panic_payload: Option<Box<dyn Any + Send>>,
+    // The Unit context that owns this data, once allocated.
+    pub(crate) ctx: *mut nxt_unit_ctx_t,
+    // Lifecycle callbacks set through the `UnitBuilder`.
+    on_ready: Option<Box<dyn FnOnce()>>,
+    on_quit: Option<Box<dyn FnOnce()>>,
+    drain_period: Option<Duration>,
+    // Set while this context is counted in `LIVE_CONTEXTS`.
+    live: bool,
+    pub(crate) panic_policy: PanicPolicy,
+    pub(crate) websockets: HashMap<*mut nxt_unit_request_info_t, WebSocketState>,
+    // WebSockets that joined a hub, by their unique ID.
+    pub(crate) hub_sockets: HashMap<u64, HubSocket>,
+    // Set to false once the Unit context is destroyed.
+    pub(crate) context_alive: Rc<Cell<bool>>,
+    // Ports registered by Unit for this context, polled by the event loop.
+    pub(crate) ports: Vec<*mut nxt_unit_port_t>,
+    pub(crate) mailbox: Arc<ContextMailbox>,
+    pub(crate) quit: bool,
+    // The state of detached requests that were not yet finished.
+    pub(crate) detached_requests: HashMap<*mut nxt_unit_request_info_t, Rc<DetachedState>>,
+    // Detached requests waiting for a completer from another thread.
+    pub(crate) parked_requests: HashMap<u64, DetachedRequest>,
+    // Set when the request currently being handled was detached.
+    pub(crate) request_detached: bool,
+    // Futures spawned on this context's executor, by their task ID.
+    pub(crate) tasks: HashMap<u64, (LocalTask, Arc<TaskWaker>)>,
+    pub(crate) next_task_id: u64,
+    // Requests whose body is still being received.
+    pub(crate) pending_bodies: HashMap<*mut nxt_unit_request_info_t, HeadersAction>,
+    // Wakers of futures waiting for more body data, by request.
+    pub(crate) body_wakers: HashMap<*mut nxt_unit_request_info_t, Waker>,
+    // Requests read with `Unit::dequeue_request()` that were not yet
+    // dispatched, with a flag set once they are finished.
+    pub(crate) queued_requests: HashMap<*mut nxt_unit_request_info_t, Rc<Cell<bool>>>,
+    // Callbacks scheduled on this context's event loop.
+    pub(crate) timers: Vec<Timer>,
+    // File descriptors registered with a mio registry.
+    #[cfg(feature = "mio")]
+    pub(crate) mio_fds: Vec<RawFd>,
+}
+
+impl ContextData {
+    fn new(builder: &mut UnitBuilder) -> Result<Self, UnitInitError> {
+        if builder.panic_policy != PanicPolicy::Propagate {
+            panic::install_backtrace_hook();
+        }
+
+        let mailbox = ContextMailbox::new().map_err(|_| UnitInitError)?;
+
+        Ok(ContextData {
+            request_handler: None,
+            unit_is_ready: false,
+            panic_payload: None,
+            ctx: std::ptr::null_mut(),
+            on_ready: builder.on_ready.take(),
+            on_quit: builder.on_quit.take(),
+            drain_period: builder.drain_period,
+            live: false,
+            panic_policy: builder.panic_policy,
+            websockets: HashMap::new(),
+            hub_sockets: HashMap::new(),
+            context_alive: Rc::new(Cell::new(true)),
+            ports: Vec::new(),
+            mailbox: Arc::new(mailbox),
+            quit: false,
+            detached_requests: HashMap::new(),
+            parked_requests: HashMap::new(),
+            request_detached: false,
+            tasks: HashMap::new(),
+            next_task_id: 0,
+            pending_bodies: HashMap::new(),
+            body_wakers: HashMap::new(),
+            queued_requests: HashMap::new(),
+            timers: Vec::new(),
+            #[cfg(feature = "mio")]
+            mio_fds: Vec::new(),
+        })
+    }
 }
 
 unsafe extern "C" fn ready_handler(ctx: *mut nxt_unit_ctx_t) -> i32 {
@@ -70,6 +458,13 @@ unsafe extern "C" fn ready_handler(ctx: *mut nxt_unit_ctx_t) -> i32 {
 
     context_data.unit_is_ready = true;
 
+    if let Some(on_ready) = context_data.on_ready.take() {
+        // The panic is resumed once the ready loop in `Unit::new()` ends.
+        if let Err(panic_payload) = std::panic::catch_unwind(AssertUnwindSafe(on_ready)) {
+            context_data.panic_payload = Some(panic_payload);
+        }
+    }
+
     nxt_unit::NXT_UNIT_OK as i32
 }
 
@@ -80,6 +475,40 @@ enum MainContext {
     Uninitialized,
     InitFailed(UnitInitError),
     Initialized(Weak<UnitContextWrapper>),
+    // A `UnitQuitHandle` was used; no new contexts will be created.
+    Quit,
+}
+
+// The mailboxes of all contexts, used to ask them to quit. Only accessed while
+// the main context's mutex is held.
+static CONTEXT_MAILBOXES: Mutex<Vec<Weak<ContextMailbox>>> = Mutex::new(Vec::new());
+
+fn register_mailbox(mailbox: &Arc<ContextMailbox>) {
+    let mut mailboxes = CONTEXT_MAILBOXES
+        .lock()
+        .expect("Context mailboxes should not be poisoned");
+
+    mailboxes.retain(|mailbox| mailbox.strong_count() > 0);
+    mailboxes.push(Arc::downgrade(mailbox));
+}
+
+// Called once no contexts are left, after one of them left its run loop.
+static PROCESS_QUIT_HANDLER: Mutex<Option<Box<dyn FnOnce() + Send>>> = Mutex::new(None);
+// Contexts count from their creation until their run loop ends or they are
+// dropped, so that contexts which have not entered their run loop yet (e.g.
+// the workers of `Unit::run_threads()`) keep the process handler from running.
+static LIVE_CONTEXTS: AtomicUsize = AtomicUsize::new(0);
+static RUN_LOOP_ENDED: AtomicBool = AtomicBool::new(false);
+
+fn run_process_quit_handler() {
+    let on_process_quit = PROCESS_QUIT_HANDLER
+        .lock()
+        .expect("Process quit handler should not be poisoned")
+        .take();
+
+    if let Some(on_process_quit) = on_process_quit {
+        on_process_quit();
+    }
 }
 
 fn main_context() -> MutexGuard<'static, MainContext> {
@@ -120,19 +549,43 @@ impl Unit {
     /// the current thread.
     ///
     /// If called after a previous [`Unit`] was constructed but already received
-    /// a QUIT event from the Unit server, this will return a no-op [`Unit`]
-    /// instance whose [`Unit::run`] method will immediately return.
+    /// a QUIT event from the Unit server, or after a [`UnitQuitHandle`] was
+    /// used, this will return a no-op [`Unit`] instance whose [`Unit::run`]
+    /// method will immediately return.
     ///
     /// If called after a previous [`Unit`] failed to initialize, this will
     /// return the same initialization failure.
+    ///
+    /// See [`Unit::builder()`] for setting options and callbacks.
     pub fn new() -> Result<Self, UnitInitError> {
+        Self::with_builder(UnitBuilder::new()).map_err(|_| UnitInitError)
+    }
+
+    /// Create a builder for a new Unit context, which allows tuning `libunit`
+    /// and setting lifecycle callbacks.
+    pub fn builder() -> UnitBuilder {
+        UnitBuilder::new()
+    }
+
+    pub(crate) fn with_builder(mut builder: UnitBuilder) -> Result<Self, UnitBuildError> {
         let mut main_context = main_context();
 
+        // Options for `nxt_unit_init()` only apply to the first context.
+        if matches!(&*main_context, MainContext::Initialized(_)) && builder.has_init_options() {
+            return Err(UnitBuildError::AlreadyInitialized);
+        }
+
         let main_unit_context = match &*main_context {
             MainContext::InitFailed(UnitInitError) => {
-                return Err(UnitInitError);
+                return Err(UnitBuildError::Init(UnitInitError));
             }
             MainContext::Uninitialized => None,
+            MainContext::Quit => {
+                return Ok(Self {
+                    context_wrapper: None,
+                    context_data: std::ptr::null_mut(),
+                });
+            }
             MainContext::Initialized(main_unit_context) => {
                 match main_unit_context.upgrade() {
                     Some(context) => Some(context),
@@ -151,11 +604,7 @@ impl Unit {
         if let Some(main_unit_context) = main_unit_context {
             // Additional contexts are created from the first.
 
-            let context_data = Box::new(ContextData {
-                request_handler: None,
-                unit_is_ready: false,
-                panic_payload: None,
-            });
+            let context_data = Box::new(ContextData::new(&mut builder)?);
 
             let context_user_data = Box::into_raw(context_data);
 
@@ -169,15 +618,26 @@ impl Unit {
             let ctx = match NonNull::new(ctx) {
                 Some(ctx) => ctx,
                 None => {
-                    return Err(UnitInitError);
+                    // SAFETY: Unit did not keep a reference to the box.
+                    unsafe { drop(Box::from_raw(context_user_data)) };
+                    return Err(UnitInitError.into());
                 }
             };
 
+            // SAFETY: The context is not in use yet.
+            unsafe { (*context_user_data).ctx = ctx.as_ptr() };
+
             let context_wrapper = UnitContextWrapper {
                 parent_context: Some(main_unit_context.clone()),
                 context: ctx,
             };
 
+            // SAFETY: The context data was just allocated, and is not in use.
+            register_mailbox(unsafe { &(*context_user_data).mailbox });
+
+            LIVE_CONTEXTS.fetch_add(1, Ordering::AcqRel);
+            unsafe { (*context_user_data).live = true };
+
             Ok(Self {
                 context_wrapper: Some(Arc::new(context_wrapper)),
                 context_data: context_user_data,
@@ -185,51 +645,72 @@ impl Unit {
         } else {
             // First context ever created.
 
-            let context_data = Box::new(ContextData {
-                request_handler: None,
-                unit_is_ready: false,
-                panic_payload: None,
-            });
+            let context_data = Box::new(ContextData::new(&mut builder)?);
 
             let context_user_data = Box::into_raw(context_data);
 
             let ctx = unsafe {
                 let mut init: nxt_unit_init_t = std::mem::zeroed();
                 init.callbacks.request_handler = Some(request_handler);
+                init.callbacks.data_handler = Some(data_handler);
                 init.callbacks.ready_handler = Some(ready_handler);
+                init.callbacks.websocket_handler = Some(websocket::websocket_handler);
+                init.callbacks.close_handler = Some(close_handler);
+                init.callbacks.add_port = Some(event_loop::add_port);
+                init.callbacks.remove_port = Some(event_loop::remove_port);
+                init.callbacks.quit = Some(event_loop::quit_handler);
 
                 init.ctx_data = context_user_data as *mut c_void;
 
+                if let Some(on_process_quit) = builder.on_process_quit.take() {
+                    *PROCESS_QUIT_HANDLER
+                        .lock()
+                        .expect("Process quit handler should not be poisoned") =
+                        Some(on_process_quit);
+                }
+
+                // Validated by the builder; zero means the library default.
+                if let Some(max_pending_requests) = builder.max_pending_requests {
+                    init.max_pending_requests = max_pending_requests as i32;
+                }
+                init.request_data_size = builder.request_data_size.unwrap_or(0);
+                init.shm_limit = builder.shm_limit.unwrap_or(0);
+                init.request_limit = builder.request_limit.unwrap_or(0);
+
                 nxt_unit_init(&mut init)
             };
 
             let ctx = match NonNull::new(ctx) {
                 Some(ctx) => ctx,
                 None => {
+                    // SAFETY: Unit did not keep a reference to the box.
+                    unsafe { drop(Box::from_raw(context_user_data)) };
                     *main_context = MainContext::InitFailed(UnitInitError);
-                    return Err(UnitInitError);
+                    return Err(UnitInitError.into());
                 }
             };
 
-            // Run until the ready handler is called.
-            loop {
-                let rc = unsafe { nxt_unit::nxt_unit_run_once(ctx.as_ptr()) };
-
-                if rc != nxt_unit::NXT_UNIT_OK as i32 {
-                    *main_context = MainContext::InitFailed(UnitInitError);
-                    return Err(UnitInitError);
-                }
+            // SAFETY: Unit's callbacks only run while the context is used.
+            unsafe { (*context_user_data).ctx = ctx.as_ptr() };
 
-                // Check if the ready handler was called.
+            // Run until the ready handler is called. The ports are
+            // non-blocking, so this waits for them with the same event loop
+            // as Unit::run(), which treats NXT_UNIT_AGAIN as "no messages yet"
+            // instead of an error.
+            loop {
                 // SAFETY: This data is thread-specific, and not shared
-                // anywhere.
+                // anywhere; no references to it are alive while polling.
                 unsafe {
-                    let context_data = (*ctx.as_ptr()).data as *mut ContextData;
-                    let context_data = &mut *context_data;
-
-                    if context_data.unit_is_ready {
+                    if (*context_user_data).unit_is_ready {
                         break;
                     }
+
+                    if !event_loop::poll_context(ctx.as_ptr(), None)
+                        && !(*context_user_data).unit_is_ready
+                    {
+                        *main_context = MainContext::InitFailed(UnitInitError);
+                        return Err(UnitInitError.into());
+                    }
                 }
             }
 
@@ -242,19 +723,84 @@ impl Unit {
             // spawned from it.
             *main_context = MainContext::Initialized(Arc::downgrade(&context_wrapper));
 
-            Ok(Self {
+            // SAFETY: Unit's callbacks no longer run until the context is used.
+            register_mailbox(unsafe { &(*context_user_data).mailbox });
+
+            LIVE_CONTEXTS.fetch_add(1, Ordering::AcqRel);
+            unsafe { (*context_user_data).live = true };
+
+            let mut unit = Self {
                 context_wrapper: Some(context_wrapper),
                 context_data: context_user_data,
-            })
+            };
+
+            // Resume any panic from the `on_ready` callback.
+            if let Some(panic_payload) = unit.context_data_mut().panic_payload.take() {
+                drop(main_context);
+                std::panic::resume_unwind(panic_payload);
+            }
+
+            Ok(unit)
         }
     }
 
-    fn context_data_mut(&mut self) -> &mut ContextData {
+    /// Return a handle that can be used to stop all Unit contexts, from any
+    /// thread.
+    pub fn quit_handle(&self) -> UnitQuitHandle {
+        UnitQuitHandle { _private: () }
+    }
+
+    #[cfg(feature = "mio")]
+    pub(crate) fn is_active(&self) -> bool {
+        self.context_wrapper.is_some()
+    }
+
+    pub(crate) fn context_data_mut(&mut self) -> &mut ContextData {
         // SAFETY: The only other thing that can access this is `.run()`, which
         // requires `&mut self` and therefore guaranteed not to be active.
         unsafe { &mut *self.context_data }
     }
 
+    // The drain period is skipped if the loop stopped because of a panic.
+    fn drain_period(&mut self) -> Option<Duration> {
+        let context_data = self.context_data_mut();
+
+        match context_data.panic_payload {
+            Some(_) => None,
+            None => context_data.drain_period,
+        }
+    }
+
+    // Stop counting this context as live, and return whether it was the last
+    // one.
+    fn leave_live_contexts(&mut self) -> bool {
+        let context_data = self.context_data_mut();
+
+        if !context_data.live {
+            return false;
+        }
+
+        context_data.live = false;
+        LIVE_CONTEXTS.fetch_sub(1, Ordering::AcqRel) == 1
+    }
+
+    // Call the context's quit handler, and the process-wide one if this is
+    // the last live context.
+    fn run_quit_handlers(&mut self) {
+        let on_quit = self.context_data_mut().on_quit.take();
+
+        RUN_LOOP_ENDED.store(true, Ordering::Release);
+        let last_context = self.leave_live_contexts();
+
+        if let Some(on_quit) = on_quit {
+            on_quit();
+        }
+
+        if last_context {
+            run_process_quit_handler();
+        }
+    }
+
     /// Set a request handler for the Unit application.
     ///
     /// The handler must be an object that implements the [`UnitService`] trait.
@@ -269,26 +815,490 @@ impl Unit {
         self.context_data_mut().request_handler = Some(Box::new(f))
     }
 
+    /// Set what this context does when one of its request handlers panics.
+    ///
+    /// By default, the panic is propagated, and resumed on this thread.
+    ///
+    /// Other policies log the panic's backtrace, which requires a panic hook.
+    /// The first time such a policy is set, a hook is installed that records
+    /// the backtrace and then calls the previously installed hook, so hooks
+    /// installed by the application keep working. Hooks installed by the
+    /// application afterwards replace it, in which case backtraces are simply
+    /// not logged.
+    pub fn set_panic_policy(&mut self, panic_policy: PanicPolicy) {
+        if self.context_wrapper.is_none() {
+            return;
+        }
+        if panic_policy != PanicPolicy::Propagate {
+            panic::install_backtrace_hook();
+        }
+        self.context_data_mut().panic_policy = panic_policy;
+    }
+
+    /// Set a request handler that is shared with other contexts.
+    ///
+    /// The same handler can be installed on every context, or used with
+    /// [`Unit::run_threads()`] through the [`SharedHandler`] adapter.
+    pub fn set_shared_handler(&mut self, handler: Arc<dyn SharedUnitService>) {
+        self.set_request_handler(SharedHandler::new(handler))
+    }
+
+    /// Set an asynchronous request handler for the Unit application.
+    ///
+    /// The handler must be an object that implements the [`AsyncUnitService`]
+    /// trait, whose futures will be executed on this context's thread, while
+    /// other requests are handled.
+    ///
+    /// This trait is automatically implemented for functions or lambda
+    /// functions that take an [`AsyncRequest`](crate::AsyncRequest) object
+    /// and return a future with a [`UnitResult<()>`](UnitResult) output.
+    pub fn set_async_request_handler(&mut self, f: impl AsyncUnitService + 'static) {
+        self.set_request_handler(AsyncHandler::new(f))
+    }
+
     /// Enter the main event loop, handling requests on this thread until the
     /// Unit server exits or requests a restart.
     ///
     /// This may be executed in parallel with other threads that call
     /// [`Unit::run()`]
+    ///
+    /// Besides Unit's own messages, the event loop also executes work sent to
+    /// this context from other threads, such as messages broadcast through a
+    /// [`WebSocketHub`](crate::websocket::WebSocketHub).
+    ///
+    /// # Panics
+    /// With the default [`PanicPolicy`], if a handler or task panics, the
+    /// event loop stops and the panic is resumed from this method, after
+    /// the request was finished with an error.
     pub fn run(&mut self) {
         if let Some(context_wrapper) = &self.context_wrapper {
-            // SAFETY: Call via FFI into Unit's main loop. It will call back into
-            // Rust code using callbacks, which must use catch_unwind to be
+            // SAFETY: Unit's ports are processed via FFI, which will call back
+            // into Rust code using callbacks, which must use catch_unwind to be
             // FFI-safe.
+            let ctx = context_wrapper.context.as_ptr();
+
             unsafe {
-                nxt_unit_run(context_wrapper.context.as_ptr());
+                while event_loop::poll_context(ctx, None) {}
+
+                if let Some(drain_period) = self.drain_period() {
+                    event_loop::drain_context(ctx, drain_period);
+                }
+            }
+
+            self.run_quit_handlers();
+
+            // Resume any panic caught in Unit's callbacks or in the context's
+            // tasks, now that no C frames are on the stack.
+            if let Some(panic_payload) = self.context_data_mut().panic_payload.take() {
+                std::panic::resume_unwind(panic_payload);
+            }
+        }
+    }
+
+    /// Wait for up to `timeout` for events, process them, and return.
+    ///
+    /// This allows embedding Unit in an existing event loop: wait until one of
+    /// the file descriptors returned by [`Unit::poll_fds()`] is readable, then
+    /// call this method with a zero timeout. If `timeout` is `None`, this
+    /// waits until an event arrives.
+    ///
+    /// Returns false once the context was asked to quit. Unlike
+    /// [`Unit::run()`], this does not wait for the drain period nor call the
+    /// quit callbacks set with the [`UnitBuilder`].
+    ///
+    /// # Panics
+    /// With the default [`PanicPolicy`], if a handler or task panics, the
+    /// panic is resumed from this method.
+    pub fn run_once(&mut self, timeout: Option<Duration>) -> bool {
+        self.poll_sources(timeout, EventSources::All)
+    }
+
+    /// Handle requests for up to `duration`, then return.
+    ///
+    /// Returns false if the context was asked to quit in the meantime, in
+    /// which case it may return earlier. See [`Unit::run_once()`].
+    pub fn run_for(&mut self, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+
+        loop {
+            let now = Instant::now();
+
+            if now >= deadline {
+                return true;
+            }
+
+            if !self.run_once(Some(deadline - now)) {
+                return false;
+            }
+        }
+    }
+
+    /// Return the file descriptors on which this context waits for events.
+    ///
+    /// These become readable when [`Unit::run_once()`] has work to do. Since
+    /// Unit may add or remove ports while processing events, the list should
+    /// be retrieved again after each call to [`Unit::run_once()`].
+    pub fn poll_fds(&self) -> Vec<RawFd> {
+        if self.context_wrapper.is_none() {
+            return Vec::new();
+        }
+
+        // SAFETY: This is only read on the context's thread, while none of its
+        // callbacks are running.
+        unsafe {
+            let mut fds = vec![(*self.context_data).mailbox.read_fd()];
+            fds.extend(
+                event_loop::context_ports(self.context_data)
+                    .into_iter()
+                    .map(|port| (*port).in_fd),
+            );
+            fds
+        }
+    }
+
+    /// Call a function once on this context's thread, after `delay`.
+    ///
+    /// The function runs in between request handlers, while the context's
+    /// event loop is running, so it can access the same state as the request
+    /// handler, e.g. through an [`Rc`]. Panics are handled according to the
+    /// context's [`PanicPolicy`].
+    ///
+    /// When using [`Unit::run_async()`], the runtime must have its time driver
+    /// enabled.
+    pub fn schedule_once(&mut self, delay: Duration, f: impl FnOnce() + 'static) -> TimerHandle {
+        self.schedule(delay, TimerCallback::Once(Box::new(f)))
+    }
+
+    /// Call a function repeatedly on this context's thread, every `interval`,
+    /// starting after the first `interval`.
+    ///
+    /// If the event loop falls behind, missed calls are skipped. See
+    /// [`Unit::schedule_once()`] for how the function is called.
+    ///
+    /// # Panics
+    /// Panics if `interval` is zero.
+    pub fn schedule_interval(
+        &mut self,
+        interval: Duration,
+        f: impl FnMut() + 'static,
+    ) -> TimerHandle {
+        assert!(
+            !interval.is_zero(),
+            "The interval must be greater than zero"
+        );
+        self.schedule(interval, TimerCallback::Interval(Box::new(f), interval))
+    }
+
+    fn schedule(&mut self, delay: Duration, callback: TimerCallback) -> TimerHandle {
+        if self.context_wrapper.is_none() {
+            return TimerHandle::cancelled();
+        }
+
+        // SAFETY: This requires `&mut self`, so the event loop is not running.
+        unsafe { timer::schedule(self.context_data, delay, callback) }
+    }
+
+    /// Process the messages for requests this context already received, such
+    /// as request bodies and WebSocket frames, as well as work sent from other
+    /// threads, without reading new requests from Unit's shared queue.
+    ///
+    /// This is the equivalent of one iteration of `libunit`'s
+    /// `nxt_unit_run_ctx()`, and can be combined with
+    /// [`Unit::run_shared()`] or [`Unit::dequeue_request()`] in order to
+    /// schedule new requests separately from existing ones.
+    ///
+    /// Waits for up to `timeout`, or indefinitely if `None`. Returns false
+    /// once the context was asked to quit.
+    ///
+    /// # Panics
+    /// With the default [`PanicPolicy`], if a handler or task panics, the
+    /// panic is resumed from this method.
+    pub fn run_ctx(&mut self, timeout: Option<Duration>) -> bool {
+        self.poll_sources(timeout, EventSources::Context)
+    }
+
+    /// Read new requests from Unit's shared queue and handle them, without
+    /// processing the messages of requests this context already received.
+    ///
+    /// This is the equivalent of one iteration of `libunit`'s
+    /// `nxt_unit_run_shared()`. Scheduled timers are not run by this method.
+    ///
+    /// Waits for up to `timeout`, or indefinitely if `None`. Returns false
+    /// once the context was asked to quit.
+    ///
+    /// # Panics
+    /// With the default [`PanicPolicy`], if a handler panics, the panic is
+    /// resumed from this method.
+    pub fn run_shared(&mut self, timeout: Option<Duration>) -> bool {
+        self.poll_sources(timeout, EventSources::Shared)
+    }
+
+    /// Read the next request from Unit's shared queue without handling it.
+    ///
+    /// Returns `None` if there are no new requests at the moment. The request
+    /// can be inspected and later passed to this context's request handler
+    /// with [`QueuedRequest::dispatch()`]; meanwhile, [`Unit::run_ctx()`]
+    /// keeps receiving the bodies of queued requests.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use std::time::Duration;
+    ///
+    /// use unit_rs::{Request, Unit};
+    ///
+    /// fn main() {
+    ///     let mut unit = Unit::new().unwrap();
+    ///
+    ///     unit.set_request_handler(|req: Request| {
+    ///         req.send_response(200, &[("Content-Type", "text/plain")], "Hello!\n")
+    ///     });
+    ///
+    ///     let mut queue = Vec::new();
+    ///
+    ///     while unit.run_ctx(Some(Duration::from_millis(1))) {
+    ///         while let Some(req) = unit.dequeue_request() {
+    ///             queue.push(req);
+    ///         }
+    ///
+    ///         // Health checks are served first.
+    ///         queue.sort_by_key(|req| req.request().map_or(true, |req| req.path() != "/health"));
+    ///
+    ///         for req in queue.drain(..) {
+    ///             req.dispatch();
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub fn dequeue_request(&mut self) -> Option<QueuedRequest> {
+        let ctx = self.context_wrapper.as_ref()?.context.as_ptr();
+
+        if self.context_data_mut().quit {
+            return None;
+        }
+
+        // SAFETY: This requires `&mut self`, so no handlers of this context
+        // are running. Returned requests were not handled yet.
+        unsafe {
+            let req = nxt_unit::nxt_unit_dequeue_request(ctx);
+
+            if req.is_null() {
+                None
+            } else {
+                Some(QueuedRequest::new(req))
+            }
+        }
+    }
+
+    fn poll_sources(&mut self, timeout: Option<Duration>, sources: EventSources) -> bool {
+        let ctx = match &self.context_wrapper {
+            Some(context_wrapper) => context_wrapper.context.as_ptr(),
+            None => return false,
+        };
+
+        // SAFETY: This requires `&mut self`, so no references to the context's
+        // data are alive.
+        let running = unsafe { event_loop::poll_sources(ctx, timeout, sources) };
+
+        if let Some(panic_payload) = self.context_data_mut().panic_payload.take() {
+            std::panic::resume_unwind(panic_payload);
+        }
+
+        running
+    }
+
+    /// Handle requests on this context and on additional worker threads,
+    /// until the Unit server exits or requests a restart.
+    ///
+    /// The `factory` is called once on each thread, including the current
+    /// one, in order to create a fresh request handler for that thread's
+    /// context. If `threads` is `None`, the number of threads is taken from
+    /// [`std::thread::available_parallelism()`].
+    ///
+    /// All threads are joined before this method returns. Once this context
+    /// quits, or any thread panics or fails to initialize, all other contexts
+    /// are asked to quit as well, with a [`UnitQuitHandle`].
+    ///
+    /// # Panics
+    /// The first panic from any of the threads is resumed on the current
+    /// thread, after all threads were joined.
+    pub fn run_threads<F, S>(
+        &mut self,
+        threads: Option<usize>,
+        factory: F,
+    ) -> Result<(), UnitInitError>
+    where
+        F: Fn() -> S + Sync,
+        S: UnitService + 'static,
+    {
+        if self.context_wrapper.is_none() {
+            return Ok(());
+        }
+
+        let threads = threads
+            .unwrap_or_else(|| {
+                std::thread::available_parallelism()
+                    .map(|threads| threads.get())
+                    .unwrap_or(1)
+            })
+            .max(1);
+
+        let quit_handle = self.quit_handle();
+        let first_panic: Mutex<Option<Box<dyn Any + Send>>> = Mutex::new(None);
+
+        // Run a context, and stop all others if it failed.
+        let run_context = |run: &mut dyn FnMut() -> Result<(), UnitInitError>| {
+            let result = std::panic::catch_unwind(AssertUnwindSafe(run));
+            quit_handle.quit();
+
+            match result {
+                Ok(result) => result,
+                Err(panic_payload) => {
+                    let mut first_panic = first_panic.lock().unwrap_or_else(|err| err.into_inner());
+                    first_panic.get_or_insert(panic_payload);
+                    Ok(())
+                }
             }
+        };
+
+        // The workers' contexts may not be created yet when this one quits, so
+        // they are counted as one live context until they are all joined.
+        LIVE_CONTEXTS.fetch_add(1, Ordering::AcqRel);
+
+        let results = std::thread::scope(|scope| {
+            let workers: Vec<_> = (1..threads)
+                .map(|_| {
+                    scope.spawn(|| {
+                        run_context(&mut || {
+                            let mut unit = Unit::new()?;
+                            unit.set_request_handler(factory());
+                            unit.run();
+                            Ok(())
+                        })
+                    })
+                })
+                .collect();
 
-            // Resume any panics forwarded through the C FFI.
-            // TODO: This is not yet functional, see catch_unwind above.
+            let mut results = vec![run_context(&mut || {
+                self.set_request_handler(factory());
+                self.run();
+                Ok(())
+            })];
+
+            for worker in workers {
+                results.push(worker.join().expect("Worker panics are caught"));
+            }
+
+            results
+        });
+
+        if LIVE_CONTEXTS.fetch_sub(1, Ordering::AcqRel) == 1
+            && RUN_LOOP_ENDED.load(Ordering::Acquire)
+        {
+            run_process_quit_handler();
+        }
+
+        if let Some(panic_payload) = first_panic
+            .into_inner()
+            .unwrap_or_else(|err| err.into_inner())
+        {
+            std::panic::resume_unwind(panic_payload);
+        }
+
+        results.into_iter().collect()
+    }
+
+    /// Handle requests on this thread as part of a [`tokio`] runtime, until
+    /// the Unit server exits or requests a restart.
+    ///
+    /// Unit's ports are registered with tokio's reactor, and processed
+    /// whenever they become readable, instead of blocking the thread.
+    ///
+    /// Since [`Unit`] is not [`Send`], the returned future must be executed on
+    /// the current thread, e.g. with a current-thread runtime's `block_on()`,
+    /// or with a [`LocalSet`](tokio::task::LocalSet).
+    ///
+    /// If a [drain period](crate::UnitBuilder::drain_period) is set, the
+    /// runtime must have its time driver enabled.
+    ///
+    /// Returns an error if the ports could not be registered with the
+    /// reactor.
+    #[cfg(feature = "tokio")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "tokio")))]
+    pub async fn run_async(&mut self) -> std::io::Result<()> {
+        if let Some(context_wrapper) = &self.context_wrapper {
+            let ctx = context_wrapper.context.as_ptr();
+
+            // SAFETY: The future borrows this object mutably, so the context
+            // outlives it, and no other references to its data are alive.
+            let result = unsafe {
+                match tokio_loop::run_context(ctx).await {
+                    Ok(()) => match self.drain_period() {
+                        Some(drain_period) => tokio_loop::drain_context(ctx, drain_period).await,
+                        None => Ok(()),
+                    },
+                    Err(err) => Err(err),
+                }
+            };
+
+            self.run_quit_handlers();
+            result?;
+
+            // Resume any panic caught in Unit's callbacks or in the context's
+            // tasks, now that no C frames are on the stack.
             if let Some(panic_payload) = self.context_data_mut().panic_payload.take() {
                 std::panic::resume_unwind(panic_payload);
             }
         }
+
+        Ok(())
+    }
+}
+
+/// A handle that stops all [`Unit`] contexts in the process.
+///
+/// This object is [`Send`] and [`Sync`], and can be obtained with
+/// [`Unit::quit_handle()`]. It can be used from any thread, for example from a
+/// thread that waits for signals, or from a request handler that serves an
+/// administration endpoint.
+///
+/// Since it locks a mutex, it must not be used directly inside a signal
+/// handler.
+#[derive(Debug, Clone)]
+pub struct UnitQuitHandle {
+    _private: (),
+}
+
+impl UnitQuitHandle {
+    /// Ask all Unit contexts to quit.
+    ///
+    /// Each context's [`Unit::run()`] loop returns after finishing its current
+    /// work and its [drain period](crate::UnitBuilder::drain_period), if any.
+    /// Contexts created afterwards do nothing, as if Unit itself had asked the
+    /// application to quit.
+    ///
+    /// If Unit was not initialized yet, or failed to initialize, this does
+    /// nothing, and later calls to [`Unit::new()`] behave as usual.
+    pub fn quit(&self) {
+        let mut main_context = main_context();
+
+        if !matches!(*main_context, MainContext::Initialized(_)) {
+            return;
+        }
+
+        *main_context = MainContext::Quit;
+
+        let mailboxes = CONTEXT_MAILBOXES
+            .lock()
+            .expect("Context mailboxes should not be poisoned");
+
+        for mailbox in mailboxes.iter().filter_map(Weak::upgrade) {
+            mailbox.post(Box::new(|context_data| {
+                // SAFETY: Tasks are executed on the context's thread, by its
+                // event loop.
+                unsafe { (*context_data).quit = true };
+            }));
+        }
     }
 }
 
@@ -316,11 +1326,64 @@ impl Drop for UnitContextWrapper {
 
 impl Drop for Unit {
     fn drop(&mut self) {
+        if self.context_data.is_null() {
+            return;
+        }
+
+        // A context dropped without running its loop may still be the last one,
+        // e.g. a worker whose service factory panicked.
+        if self.leave_live_contexts() && RUN_LOOP_ENDED.load(Ordering::Acquire) {
+            run_process_quit_handler();
+        }
+
+        // Requests waiting for other threads, as well as any requests held by
+        // the request handler, are finished while the context still exists.
+        // SAFETY: The run loop is not active, and this is the only user of the
+        // context data.
+        unsafe {
+            let parked_requests = std::mem::take(&mut (*self.context_data).parked_requests);
+            drop(parked_requests);
+
+            let tasks = std::mem::take(&mut (*self.context_data).tasks);
+            drop(tasks);
+
+            let timers = std::mem::take(&mut (*self.context_data).timers);
+            drop(timers);
+
+            let request_handler = (*self.context_data).request_handler.take();
+            drop(request_handler);
+        }
+
+        // Callbacks for this context may still be called while it is being
+        // destroyed, and must not see the context data anymore.
+        if let Some(context_wrapper) = &self.context_wrapper {
+            unsafe {
+                (*context_wrapper.context.as_ptr()).data = std::ptr::null_mut();
+            }
+        }
+
         // SAFETY: This structure is the only owner of the box, and is being
         // dropped, therefore not currently being shared.
-        unsafe {
-            drop(Box::from_raw(self.context_data));
+        let mut context_data = unsafe { Box::from_raw(self.context_data) };
+        let context_alive = context_data.context_alive.clone();
+
+        // Work sent from other threads will no longer be executed.
+        drop(context_data.mailbox.close());
+
+        // Any WebSocket handles that are still held somewhere must not use
+        // the requests after the context is gone.
+        for state in context_data.websockets.values() {
+            state.socket().mark_closed();
         }
+        websocket::forget_hub_sockets(&mut context_data);
+        for state in context_data.detached_requests.values() {
+            state.mark_finished();
+        }
+        for finished in context_data.queued_requests.values() {
+            finished.set(true);
+        }
+        drop(context_data);
+        context_alive.set(false);
 
         // Note: Everything that uses the contex must be dropped before this.
         drop(self.context_wrapper.take());
@@ -334,6 +1397,32 @@ impl Drop for Unit {
 /// that take a [`Request`] object and return a [`UnitResult<()>`](UnitResult).
 pub trait UnitService {
     fn handle_request(&mut self, req: Request) -> UnitResult<()>;
+
+    /// Inspect a request as soon as its headers arrive, before Unit buffers
+    /// the whole request body.
+    ///
+    /// The method, path, and fields of the request are available, but the
+    /// body may be incomplete. The returned [`HeadersAction`] decides what
+    /// happens with the rest of the request; returning an error finishes the
+    /// request with that error, without calling
+    /// [`handle_request()`](UnitService::handle_request).
+    ///
+    /// The default implementation waits for the whole body.
+    fn on_headers(&mut self, _req: &Request) -> UnitResult<HeadersAction> {
+        Ok(HeadersAction::Buffer)
+    }
+
+    /// Called each time more body data arrives for a request for which
+    /// [`on_headers()`](UnitService::on_headers) returned
+    /// [`HeadersAction::Stream`].
+    ///
+    /// The newly arrived data can be consumed with [`Request::read_body()`] or
+    /// [`Request::body()`]; reading does not block, and returns only the data
+    /// that arrived so far. Returning an error finishes the request with that
+    /// error.
+    fn on_body_data(&mut self, _req: &Request) -> UnitResult<()> {
+        Ok(())
+    }
 }
 
 impl<F> UnitService for F
@@ -344,3 +1433,20 @@ where
         self(req)
     }
 }
+
+/// The action to take for a request after inspecting its headers with
+/// [`UnitService::on_headers()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeadersAction {
+    /// Wait until the whole body is received, then call
+    /// [`UnitService::handle_request()`].
+    Buffer,
+    /// Call [`UnitService::on_body_data()`] each time more body data
+    /// arrives, then call [`UnitService::handle_request()`] once the whole
+    /// body was received.
+    Stream,
+    /// Finish the request without waiting for the body, e.g. after sending an
+    /// early response. If no response was sent, Unit will respond with an
+    /// error.
+    Finish,
+}
@@ -0,0 +1,496 @@
+//! This module contains support for upgrading requests to WebSocket
+//! connections.
+//!
+//! A request can be upgraded with [`Request::upgrade_websocket()`], which sends
+//! the handshake response and registers a [`WebSocketHandler`] that will
+//! receive every frame sent by the client.
+//!
+//! Most applications will instead want to use the [`MessageHandler`] adapter,
+//! which reassembles fragmented frames into complete [`WebSocketMessage`]s,
+//! answers pings, and validates text messages.
+//!
+//! Messages can be sent to groups of connections owned by different threads
+//! through a [`WebSocketHub`].
+//!
+//! # Example
+//!
+//! ```no_run
+//! use unit_rs::websocket::{WebSocket, WebSocketFrame};
+//! use unit_rs::{Request, Unit};
+//!
+//! fn main() {
+//!     let mut unit = Unit::new().unwrap();
+//!
+//!     unit.set_request_handler(|req: Request| {
+//!         req.upgrade_websocket(|ws: &WebSocket, mut frame: WebSocketFrame| {
+//!             let payload = frame.read_to_vec().unwrap();
+//!             ws.send(frame.opcode(), frame.is_final(), payload)
+//!         })?;
+//!
+//!         Ok(())
+//!     });
+//!
+//!     unit.run();
+//! }
+//! ```
+//!
+//!
+//! A message-based echo server:
+//!
+//! ```no_run
+//! use unit_rs::websocket::{MessageHandler, WebSocket, WebSocketMessage};
+//! use unit_rs::{Request, Unit};
+//!
+//! fn main() {
+//!     let mut unit = Unit::new().unwrap();
+//!
+//!     unit.set_request_handler(|req: Request| {
+//!         let handler = MessageHandler::new(|ws: &WebSocket, message| match message {
+//!             WebSocketMessage::Text(text) => ws.send_text(text),
+//!             WebSocketMessage::Binary(data) => ws.send_binary(data),
+//!             _ => Ok(()),
+//!         });
+//!         req.upgrade_websocket(handler.max_message_size(64 * 1024))?;
+//!
+//!         Ok(())
+//!     });
+//!
+//!     unit.run();
+//! }
+//! ```
+//!
+//! [`Request::upgrade_websocket()`]: crate::Request::upgrade_websocket
+
+use std::cell::Cell;
+use std::io::{IoSlice, Read};
+use std::panic::AssertUnwindSafe;
+use std::rc::Rc;
+
+use libc::c_void;
+
+use crate::error::{IntoUnitResult, UnitError, UnitResult};
+use crate::nxt_unit::{
+    self, nxt_unit_request_info_t, nxt_unit_websocket_done, nxt_unit_websocket_frame_t,
+    nxt_unit_websocket_read, nxt_unit_websocket_retain,
+};
+use crate::panic;
+use crate::request::LogLevel;
+use crate::unit::ContextData;
+use crate::Request;
+
+mod hub;
+mod message;
+
+pub use hub::{HubMessage, WebSocketHub};
+pub use message::{
+    CloseCode, CloseFrame, MessageHandler, MessageService, WebSocketMessage,
+    DEFAULT_MAX_MESSAGE_SIZE,
+};
+
+pub(crate) use hub::{forget_hub_sockets, HubSocket};
+
+/// The opcode of a WebSocket frame, as defined by
+/// [RFC 6455](https://www.rfc-editor.org/rfc/rfc6455#section-5.2).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+    /// An opcode reserved for future use by the WebSocket protocol.
+    Reserved(u8),
+}
+
+impl Opcode {
+    /// Return whether or not this is a control frame opcode (close, ping, and
+    /// pong).
+    pub fn is_control(self) -> bool {
+        self.as_u8() & nxt_unit::NXT_WEBSOCKET_OP_CTRL as u8 != 0
+    }
+
+    pub(crate) fn from_u8(opcode: u8) -> Self {
+        match opcode as u32 {
+            nxt_unit::NXT_WEBSOCKET_OP_CONT => Opcode::Continuation,
+            nxt_unit::NXT_WEBSOCKET_OP_TEXT => Opcode::Text,
+            nxt_unit::NXT_WEBSOCKET_OP_BINARY => Opcode::Binary,
+            nxt_unit::NXT_WEBSOCKET_OP_CLOSE => Opcode::Close,
+            nxt_unit::NXT_WEBSOCKET_OP_PING => Opcode::Ping,
+            nxt_unit::NXT_WEBSOCKET_OP_PONG => Opcode::Pong,
+            _ => Opcode::Reserved(opcode),
+        }
+    }
+
+    pub(crate) fn as_u8(self) -> u8 {
+        let opcode = match self {
+            Opcode::Continuation => nxt_unit::NXT_WEBSOCKET_OP_CONT,
+            Opcode::Text => nxt_unit::NXT_WEBSOCKET_OP_TEXT,
+            Opcode::Binary => nxt_unit::NXT_WEBSOCKET_OP_BINARY,
+            Opcode::Close => nxt_unit::NXT_WEBSOCKET_OP_CLOSE,
+            Opcode::Ping => nxt_unit::NXT_WEBSOCKET_OP_PING,
+            Opcode::Pong => nxt_unit::NXT_WEBSOCKET_OP_PONG,
+            Opcode::Reserved(opcode) => return opcode,
+        };
+        opcode as u8
+    }
+}
+
+/// A handle to an upgraded WebSocket connection, which can be used to send
+/// frames to the client.
+///
+/// This object is created by [`Request::upgrade_websocket()`], and is also
+/// passed to the [`WebSocketHandler`] for every received frame.
+///
+/// The handle can be cloned and stored for as long as needed, but it is
+/// neither [`Send`] nor [`Sync`], and can only be used on the thread of the
+/// [`Unit`](crate::Unit) context that received the request. After the
+/// connection closes, all attempts to send will return an error.
+#[derive(Clone)]
+pub struct WebSocket {
+    nxt_request: *mut nxt_unit_request_info_t,
+    // Unique among all connections of all contexts.
+    id: u64,
+    closed: Rc<Cell<bool>>,
+}
+
+impl WebSocket {
+    /// Send a single frame to the client.
+    ///
+    /// If `last` is false, the frame will be sent without the FIN bit, and
+    /// the message must be continued with [`Opcode::Continuation`] frames.
+    pub fn send(&self, opcode: Opcode, last: bool, payload: impl AsRef<[u8]>) -> UnitResult<()> {
+        if self.is_closed() {
+            return Err(UnitError::error());
+        }
+
+        let payload = payload.as_ref();
+
+        // SAFETY: The request is still alive, as the connection was not yet
+        // closed. Unit copies the payload into its own buffers.
+        unsafe {
+            nxt_unit::nxt_unit_websocket_send(
+                self.nxt_request,
+                opcode.as_u8(),
+                last as u8,
+                payload.as_ptr() as *const c_void,
+                payload.len() as u64,
+            )
+            .into_unit_result()
+        }
+    }
+
+    /// Send a single frame to the client, whose payload is the concatenation
+    /// of all the given slices.
+    pub fn send_vectored(
+        &self,
+        opcode: Opcode,
+        last: bool,
+        payload: &[IoSlice<'_>],
+    ) -> UnitResult<()> {
+        if self.is_closed() {
+            return Err(UnitError::error());
+        }
+
+        assert!(payload.len() <= i32::MAX as usize);
+
+        // SAFETY: The request is still alive, as the connection was not yet
+        // closed. `IoSlice` is guaranteed to be ABI-compatible with `iovec` on
+        // Unix platforms.
+        unsafe {
+            nxt_unit::nxt_unit_websocket_sendv(
+                self.nxt_request,
+                opcode.as_u8(),
+                last as u8,
+                payload.as_ptr() as *const nxt_unit::iovec,
+                payload.len() as i32,
+            )
+            .into_unit_result()
+        }
+    }
+
+    /// Return whether or not the connection was closed.
+    pub fn is_closed(&self) -> bool {
+        self.closed.get()
+    }
+
+    /// Log a message through Unit's log, associated with the request that
+    /// opened this connection.
+    pub fn log<S: AsRef<str>>(&self, level: LogLevel, message: S) {
+        if self.is_closed() {
+            return;
+        }
+
+        Request {
+            nxt_request: self.nxt_request,
+            _lifetime: Default::default(),
+        }
+        .log(level, message)
+    }
+
+    pub(crate) fn mark_closed(&self) {
+        self.closed.set(true);
+    }
+
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Return the data of the context that owns this connection.
+    ///
+    /// # Safety
+    /// The connection must not be closed.
+    pub(crate) unsafe fn context_data(&self) -> *mut ContextData {
+        (*(*self.nxt_request).ctx).data as *mut ContextData
+    }
+}
+
+/// A single frame received from a WebSocket client.
+///
+/// The frame's payload can be read through the [`Read`](std::io::Read) trait.
+/// Reading is non-blocking, as Unit buffers the whole frame before calling the
+/// [`WebSocketHandler`].
+///
+/// Frames may be stored beyond the handler callback that received them; in
+/// that case the payload is automatically copied out of Unit's shared memory
+/// once the handler returns, and released when the frame is dropped.
+pub struct WebSocketFrame {
+    nxt_frame: *mut nxt_unit_websocket_frame_t,
+    // Used to detect whether the frame outlived the handler.
+    _token: Rc<()>,
+    context_alive: Rc<Cell<bool>>,
+}
+
+impl WebSocketFrame {
+    /// Return the opcode of the frame.
+    pub fn opcode(&self) -> Opcode {
+        // SAFETY: The header is always set for frames received from Unit.
+        unsafe { Opcode::from_u8((*(*self.nxt_frame).header).opcode()) }
+    }
+
+    /// Return whether or not this is the last frame of a message (i.e. the FIN
+    /// bit is set).
+    pub fn is_final(&self) -> bool {
+        // SAFETY: The header is always set for frames received from Unit.
+        unsafe { (*(*self.nxt_frame).header).fin() != 0 }
+    }
+
+    /// Return the length of the frame's payload, in bytes.
+    pub fn payload_len(&self) -> u64 {
+        unsafe { (*self.nxt_frame).payload_len }
+    }
+
+    /// Convenience function that allocates and copies the remaining payload
+    /// data into a [`Vec<u8>`].
+    pub fn read_to_vec(&mut self) -> std::io::Result<Vec<u8>> {
+        let mut vec = Vec::with_capacity(self.payload_len() as usize);
+        self.read_to_end(&mut vec)?;
+        Ok(vec)
+    }
+}
+
+impl std::io::Read for WebSocketFrame {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if !self.context_alive.get() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "The Unit context of this frame was destroyed",
+            ));
+        }
+
+        // SAFETY: The target is user-provided and initialized. Unit will
+        // unmask the payload while copying it.
+        let bytes = unsafe {
+            nxt_unit_websocket_read(
+                self.nxt_frame,
+                buf.as_mut_ptr() as *mut c_void,
+                buf.len() as u64,
+            )
+        };
+
+        if bytes < 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "Could not read WebSocket frame payload",
+            ));
+        }
+
+        Ok(bytes as usize)
+    }
+}
+
+impl Drop for WebSocketFrame {
+    fn drop(&mut self) {
+        // Frames are allocated in the context, and freed along with it.
+        if self.context_alive.get() {
+            unsafe {
+                nxt_unit_websocket_done(self.nxt_frame);
+            }
+        }
+    }
+}
+
+/// A trait that can be implemented by WebSocket frame handlers to be used with
+/// [`Request::upgrade_websocket()`](crate::Request::upgrade_websocket).
+///
+/// This trait is automatically implemented for functions or lambda functions
+/// that take a [`&WebSocket`](WebSocket) and a [`WebSocketFrame`], and return a
+/// [`UnitResult<()>`](UnitResult).
+///
+/// If the handler returns an error, a close frame will be sent to the client.
+pub trait WebSocketHandler {
+    fn handle_frame(&mut self, ws: &WebSocket, frame: WebSocketFrame) -> UnitResult<()>;
+
+    /// Called once after the connection was closed, either by the client or by
+    /// the Unit server.
+    fn handle_close(&mut self, _ws: &WebSocket) {}
+}
+
+impl<F> WebSocketHandler for F
+where
+    F: FnMut(&WebSocket, WebSocketFrame) -> UnitResult<()> + 'static,
+{
+    fn handle_frame(&mut self, ws: &WebSocket, frame: WebSocketFrame) -> UnitResult<()> {
+        self(ws, frame)
+    }
+}
+
+/// Per-connection state, stored in the context that received the request.
+pub(crate) struct WebSocketState {
+    socket: WebSocket,
+    // Taken out while the handler is running.
+    handler: Option<Box<dyn WebSocketHandler>>,
+}
+
+impl WebSocketState {
+    pub(crate) fn socket(&self) -> &WebSocket {
+        &self.socket
+    }
+}
+
+/// Register an upgraded request in its context.
+///
+/// # Safety
+/// Must be called on the thread of the request's context, while no mutable
+/// reference to the context's data is alive.
+pub(crate) unsafe fn register(
+    nxt_request: *mut nxt_unit_request_info_t,
+    handler: Box<dyn WebSocketHandler>,
+) -> WebSocket {
+    let context_data = (*(*nxt_request).ctx).data as *mut ContextData;
+
+    let socket = WebSocket {
+        nxt_request,
+        id: hub::next_socket_id(),
+        closed: Rc::new(Cell::new(false)),
+    };
+
+    let state = WebSocketState {
+        socket: socket.clone(),
+        handler: Some(handler),
+    };
+
+    (*context_data).websockets.insert(nxt_request, state);
+
+    socket
+}
+
+pub(crate) unsafe extern "C" fn websocket_handler(frame: *mut nxt_unit_websocket_frame_t) {
+    // SAFETY: The context data is passed as Unit context-specific user data,
+    // and individual Unit contexts correspond to individual threads.
+    let req = (*frame).req;
+    let context_data = (*(*req).ctx).data as *mut ContextData;
+
+    if context_data.is_null() {
+        nxt_unit_websocket_done(frame);
+        return;
+    }
+
+    let (socket, mut handler) = match (*context_data).websockets.get_mut(&req) {
+        Some(state) => match state.handler.take() {
+            Some(handler) => (state.socket.clone(), handler),
+            None => {
+                nxt_unit_websocket_done(frame);
+                return;
+            }
+        },
+        None => {
+            nxt_unit_websocket_done(frame);
+            return;
+        }
+    };
+
+    let token = Rc::new(());
+    let ws_frame = WebSocketFrame {
+        nxt_frame: frame,
+        _token: token.clone(),
+        context_alive: (*context_data).context_alive.clone(),
+    };
+
+    // This assertion is safe because the panic payload is not examined, and
+    // the panic will just be forwarded through Unit's C FFI and resumed.
+    let result =
+        std::panic::catch_unwind(AssertUnwindSafe(|| handler.handle_frame(&socket, ws_frame)));
+
+    // Put the handler back, unless the connection was closed in the meantime.
+    if let Some(state) = (*context_data).websockets.get_mut(&req) {
+        state.handler = Some(handler);
+    }
+
+    // The frame outlived the handler; move its payload out of the shared
+    // memory segment so that it can be released. On failure, the frame will
+    // keep holding the shared memory, which is still valid.
+    if Rc::strong_count(&token) > 1 && nxt_unit_websocket_retain(frame) != 0 {
+        socket.log(
+            LogLevel::Warning,
+            "Could not retain WebSocket frame, keeping it in shared memory",
+        );
+    }
+
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(UnitError(_))) => {
+            // Ask the client to close the connection.
+            let _ = socket.send(Opcode::Close, true, []);
+        }
+        Err(panic_payload) => {
+            let _ = socket.send(Opcode::Close, true, []);
+            panic::handle_panic(context_data, "WebSocket handler", panic_payload)
+        }
+    }
+}
+
+/// Mark a connection as closed, and remove it from any hubs it joined.
+///
+/// # Safety
+/// Must be called on the context's thread, with valid context data.
+pub(crate) unsafe fn close_socket(context_data: *mut ContextData, socket: &WebSocket) {
+    socket.mark_closed();
+    hub::remove_socket(context_data, socket.id);
+}
+
+/// Called when a request was closed while its handler was not running.
+///
+/// Returns true if the request was an upgraded WebSocket connection.
+pub(crate) unsafe fn handle_close(req: *mut nxt_unit_request_info_t) -> bool {
+    let context_data = (*(*req).ctx).data as *mut ContextData;
+
+    let mut state = match (*context_data).websockets.remove(&req) {
+        Some(state) => state,
+        None => return false,
+    };
+
+    close_socket(context_data, &state.socket);
+
+    if let Some(handler) = &mut state.handler {
+        let socket = &state.socket;
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| handler.handle_close(socket)));
+
+        // The request is still finished by the close handler.
+        if let Err(panic_payload) = result {
+            panic::handle_panic(context_data, "WebSocket handler", panic_payload);
+        }
+    }
+
+    true
+}
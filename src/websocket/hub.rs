@@ -0,0 +1,415 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+
+use crate::error::{UnitError, UnitResult};
+use crate::event_loop::ContextMailbox;
+use crate::unit::ContextData;
+
+use super::{Opcode, WebSocket};
+
+static NEXT_SOCKET_ID: AtomicU64 = AtomicU64::new(1);
+
+pub(crate) fn next_socket_id() -> u64 {
+    NEXT_SOCKET_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// A message that can be sent to many WebSocket connections at once through a
+/// [`WebSocketHub`].
+///
+/// The payload is reference-counted, and is shared between all recipients
+/// until it is copied into Unit's buffers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HubMessage {
+    Text(Arc<str>),
+    Binary(Arc<[u8]>),
+}
+
+impl HubMessage {
+    fn send_to(&self, ws: &WebSocket) -> UnitResult<()> {
+        match self {
+            HubMessage::Text(text) => ws.send(Opcode::Text, true, text.as_bytes()),
+            HubMessage::Binary(data) => ws.send(Opcode::Binary, true, data),
+        }
+    }
+}
+
+impl From<&str> for HubMessage {
+    fn from(text: &str) -> Self {
+        HubMessage::Text(text.into())
+    }
+}
+
+impl From<String> for HubMessage {
+    fn from(text: String) -> Self {
+        HubMessage::Text(text.into())
+    }
+}
+
+impl From<&[u8]> for HubMessage {
+    fn from(data: &[u8]) -> Self {
+        HubMessage::Binary(data.into())
+    }
+}
+
+impl From<Vec<u8>> for HubMessage {
+    fn from(data: Vec<u8>) -> Self {
+        HubMessage::Binary(data.into())
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Group {
+    Room(String),
+    Topic(String),
+}
+
+struct Member {
+    mailbox: Arc<ContextMailbox>,
+    groups: HashSet<Group>,
+}
+
+#[derive(Default)]
+struct HubState {
+    members: HashMap<u64, Member>,
+    groups: HashMap<Group, HashSet<u64>>,
+}
+
+#[derive(Default)]
+struct HubInner {
+    state: Mutex<HubState>,
+}
+
+impl HubInner {
+    fn state(&self) -> MutexGuard<'_, HubState> {
+        self.state.lock().expect("Hub should not be poisoned")
+    }
+
+    fn remove_member(&self, socket_id: u64) {
+        let mut state = self.state();
+
+        if let Some(member) = state.members.remove(&socket_id) {
+            for group in member.groups {
+                remove_from_group(&mut state, &group, socket_id);
+            }
+        }
+    }
+}
+
+fn remove_from_group(state: &mut HubState, group: &Group, socket_id: u64) {
+    if let Some(sockets) = state.groups.get_mut(group) {
+        sockets.remove(&socket_id);
+
+        if sockets.is_empty() {
+            state.groups.remove(group);
+        }
+    }
+}
+
+/// The hub-related state of a WebSocket, stored in the context that owns it.
+pub(crate) struct HubSocket {
+    socket: WebSocket,
+    hubs: Vec<Weak<HubInner>>,
+}
+
+/// A registry of WebSocket connections that can deliver messages to groups of
+/// connections, regardless of which [`Unit`](crate::Unit) context (and
+/// therefore thread) owns them.
+///
+/// Connections can join named rooms, or subscribe to named topics. Both are
+/// independent groups of connections; rooms are meant for connections that
+/// talk to each other (e.g. a chat room), while topics are meant for
+/// connections that receive notifications from elsewhere.
+///
+/// The hub is [`Send`] and [`Sync`], and can be cloned and shared with any
+/// thread. Messages are queued for each connection's context, and are sent
+/// from that context's thread the next time its event loop runs. Connections
+/// are automatically removed from the hub once they are closed.
+///
+/// # Example
+///
+/// ```no_run
+/// use unit_rs::websocket::{MessageHandler, WebSocket, WebSocketHub, WebSocketMessage};
+/// use unit_rs::{Request, Unit};
+///
+/// fn worker(hub: WebSocketHub) {
+///     let mut unit = Unit::new().unwrap();
+///
+///     unit.set_request_handler(move |req: Request| {
+///         let hub = hub.clone();
+///         let ws = req.upgrade_websocket(MessageHandler::new(
+///             move |ws: &WebSocket, message| {
+///                 if let WebSocketMessage::Text(text) = message {
+///                     hub.send_to_room_except("lobby", text, ws);
+///                 }
+///                 Ok(())
+///             },
+///         ))?;
+///         hub.join("lobby", &ws)?;
+///
+///         Ok(())
+///     });
+///
+///     unit.run();
+/// }
+/// ```
+#[derive(Clone, Default)]
+pub struct WebSocketHub {
+    inner: Arc<HubInner>,
+}
+
+impl WebSocketHub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a connection to a room.
+    ///
+    /// Returns an error if the connection is already closed.
+    pub fn join(&self, room: &str, ws: &WebSocket) -> UnitResult<()> {
+        self.add_to_group(Group::Room(room.to_string()), ws)
+    }
+
+    /// Remove a connection from a room.
+    pub fn leave(&self, room: &str, ws: &WebSocket) {
+        self.remove_from_group(Group::Room(room.to_string()), ws)
+    }
+
+    /// Subscribe a connection to a topic.
+    ///
+    /// Returns an error if the connection is already closed.
+    pub fn subscribe(&self, topic: &str, ws: &WebSocket) -> UnitResult<()> {
+        self.add_to_group(Group::Topic(topic.to_string()), ws)
+    }
+
+    /// Unsubscribe a connection from a topic.
+    pub fn unsubscribe(&self, topic: &str, ws: &WebSocket) {
+        self.remove_from_group(Group::Topic(topic.to_string()), ws)
+    }
+
+    /// Send a message to all connections in a room.
+    ///
+    /// Returns the number of connections the message was queued for.
+    pub fn send_to_room(&self, room: &str, message: impl Into<HubMessage>) -> usize {
+        self.deliver(&Group::Room(room.to_string()), message.into(), None)
+    }
+
+    /// Send a message to all connections in a room, except for the given one
+    /// (usually the connection that sent the message).
+    ///
+    /// Returns the number of connections the message was queued for.
+    pub fn send_to_room_except(
+        &self,
+        room: &str,
+        message: impl Into<HubMessage>,
+        except: &WebSocket,
+    ) -> usize {
+        self.deliver(
+            &Group::Room(room.to_string()),
+            message.into(),
+            Some(except.id()),
+        )
+    }
+
+    /// Send a message to all connections subscribed to a topic.
+    ///
+    /// Returns the number of connections the message was queued for.
+    pub fn publish(&self, topic: &str, message: impl Into<HubMessage>) -> usize {
+        self.deliver(&Group::Topic(topic.to_string()), message.into(), None)
+    }
+
+    /// Send a message to all connections in the hub.
+    ///
+    /// Returns the number of connections the message was queued for.
+    pub fn broadcast(&self, message: impl Into<HubMessage>) -> usize {
+        let state = self.inner.state();
+        let recipients = state
+            .members
+            .iter()
+            .map(|(socket_id, member)| (*socket_id, member.mailbox.clone()))
+            .collect();
+        drop(state);
+
+        post_messages(recipients, message.into())
+    }
+
+    /// Return the number of connections in a room.
+    pub fn room_len(&self, room: &str) -> usize {
+        self.group_len(&Group::Room(room.to_string()))
+    }
+
+    /// Return the number of connections subscribed to a topic.
+    pub fn topic_len(&self, topic: &str) -> usize {
+        self.group_len(&Group::Topic(topic.to_string()))
+    }
+
+    fn group_len(&self, group: &Group) -> usize {
+        let state = self.inner.state();
+        state.groups.get(group).map(HashSet::len).unwrap_or(0)
+    }
+
+    fn add_to_group(&self, group: Group, ws: &WebSocket) -> UnitResult<()> {
+        if ws.is_closed() {
+            return Err(UnitError::error());
+        }
+
+        // SAFETY: WebSockets are not Send, so this runs on the thread of the
+        // context that owns the connection, which is still alive since the
+        // connection is not closed.
+        let mailbox = unsafe {
+            let context_data = ws.context_data();
+
+            let hub_socket = (*context_data)
+                .hub_sockets
+                .entry(ws.id())
+                .or_insert_with(|| HubSocket {
+                    socket: ws.clone(),
+                    hubs: Vec::new(),
+                });
+
+            let inner = Arc::downgrade(&self.inner);
+            if !hub_socket.hubs.iter().any(|hub| hub.ptr_eq(&inner)) {
+                hub_socket.hubs.push(inner);
+            }
+
+            (*context_data).mailbox.clone()
+        };
+
+        let mut state = self.inner.state();
+
+        let member = state.members.entry(ws.id()).or_insert_with(|| Member {
+            mailbox,
+            groups: HashSet::new(),
+        });
+        member.groups.insert(group.clone());
+
+        state.groups.entry(group).or_default().insert(ws.id());
+
+        Ok(())
+    }
+
+    fn remove_from_group(&self, group: Group, ws: &WebSocket) {
+        let mut state = self.inner.state();
+
+        let member = match state.members.get_mut(&ws.id()) {
+            Some(member) => member,
+            None => return,
+        };
+
+        member.groups.remove(&group);
+        let is_last_group = member.groups.is_empty();
+
+        if is_last_group {
+            state.members.remove(&ws.id());
+        }
+
+        remove_from_group(&mut state, &group, ws.id());
+        drop(state);
+
+        if !is_last_group || ws.is_closed() {
+            return;
+        }
+
+        // SAFETY: See `add_to_group`.
+        unsafe {
+            let context_data = ws.context_data();
+
+            if let Some(hub_socket) = (*context_data).hub_sockets.get_mut(&ws.id()) {
+                let inner = Arc::downgrade(&self.inner);
+                hub_socket.hubs.retain(|hub| !hub.ptr_eq(&inner));
+
+                if hub_socket.hubs.is_empty() {
+                    (*context_data).hub_sockets.remove(&ws.id());
+                }
+            }
+        }
+    }
+
+    fn deliver(&self, group: &Group, message: HubMessage, except: Option<u64>) -> usize {
+        let state = self.inner.state();
+
+        let recipients = match state.groups.get(group) {
+            Some(sockets) => sockets
+                .iter()
+                .filter(|socket_id| Some(**socket_id) != except)
+                .filter_map(|socket_id| {
+                    let member = state.members.get(socket_id)?;
+                    Some((*socket_id, member.mailbox.clone()))
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        drop(state);
+
+        post_messages(recipients, message)
+    }
+}
+
+/// Queue a message for each recipient, with a single task per context.
+fn post_messages(recipients: Vec<(u64, Arc<ContextMailbox>)>, message: HubMessage) -> usize {
+    let mut by_context: HashMap<*const ContextMailbox, (Arc<ContextMailbox>, Vec<u64>)> =
+        HashMap::new();
+
+    for (socket_id, mailbox) in recipients {
+        by_context
+            .entry(Arc::as_ptr(&mailbox))
+            .or_insert_with(|| (mailbox, Vec::new()))
+            .1
+            .push(socket_id);
+    }
+
+    let mut count = 0;
+
+    for (mailbox, socket_ids) in by_context.into_values() {
+        let socket_count = socket_ids.len();
+        let message = message.clone();
+
+        let posted = mailbox.post(Box::new(move |context_data| {
+            for socket_id in socket_ids {
+                // SAFETY: Tasks are executed on the context's thread, by its
+                // event loop.
+                let socket = match unsafe { (*context_data).hub_sockets.get(&socket_id) } {
+                    Some(hub_socket) => hub_socket.socket.clone(),
+                    None => continue,
+                };
+
+                // Closed connections are removed by their close handler.
+                let _ = message.send_to(&socket);
+            }
+        }));
+
+        if posted {
+            count += socket_count;
+        }
+    }
+
+    count
+}
+
+/// Remove a closed connection from all the hubs it joined.
+///
+/// # Safety
+/// Must be called on the context's thread, with valid context data.
+pub(crate) unsafe fn remove_socket(context_data: *mut ContextData, socket_id: u64) {
+    if let Some(hub_socket) = (*context_data).hub_sockets.remove(&socket_id) {
+        for hub in hub_socket.hubs {
+            if let Some(hub) = hub.upgrade() {
+                hub.remove_member(socket_id);
+            }
+        }
+    }
+}
+
+/// Remove all connections of a context that is being destroyed from their
+/// hubs.
+pub(crate) fn forget_hub_sockets(context_data: &mut ContextData) {
+    for (socket_id, hub_socket) in context_data.hub_sockets.drain() {
+        hub_socket.socket.mark_closed();
+
+        for hub in hub_socket.hubs {
+            if let Some(hub) = hub.upgrade() {
+                hub.remove_member(socket_id);
+            }
+        }
+    }
+}
@@ -0,0 +1,392 @@
+use std::io::Read;
+
+use crate::error::{UnitError, UnitResult};
+use crate::nxt_unit;
+use crate::request::LogLevel;
+
+use super::{Opcode, WebSocket, WebSocketFrame, WebSocketHandler};
+
+/// The default limit for the size of a reassembled message, in bytes.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// A complete message received from a WebSocket client.
+///
+/// Fragmented messages are reassembled before being passed to a
+/// [`MessageService`]. Ping frames are answered automatically, and are not
+/// passed to the service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WebSocketMessage {
+    Text(String),
+    Binary(Vec<u8>),
+    Pong(Vec<u8>),
+    /// The client initiated a close handshake, and a close frame is sent back
+    /// automatically. The peer's code is echoed back when it may be sent,
+    /// otherwise 1000 (Normal).
+    Close(Option<CloseFrame>),
+}
+
+/// The status code and reason sent in a WebSocket close frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: CloseCode,
+    pub reason: String,
+}
+
+/// A WebSocket close status code, as defined by
+/// [RFC 6455](https://www.rfc-editor.org/rfc/rfc6455#section-7.4.1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseCode {
+    Normal,
+    GoingAway,
+    ProtocolError,
+    UnprocessableInput,
+    Reserved,
+    NotProvided,
+    Abnormal,
+    InvalidData,
+    PolicyViolation,
+    MessageTooBig,
+    ExtensionRequired,
+    InternalServerError,
+    TlsHandshakeFailed,
+    /// Any other code, such as application-specific codes in the 4000-4999
+    /// range.
+    Other(u16),
+}
+
+impl From<u16> for CloseCode {
+    fn from(code: u16) -> Self {
+        match code as u32 {
+            nxt_unit::NXT_WEBSOCKET_CR_NORMAL => CloseCode::Normal,
+            nxt_unit::NXT_WEBSOCKET_CR_GOING_AWAY => CloseCode::GoingAway,
+            nxt_unit::NXT_WEBSOCKET_CR_PROTOCOL_ERROR => CloseCode::ProtocolError,
+            nxt_unit::NXT_WEBSOCKET_CR_UNPROCESSABLE_INPUT => CloseCode::UnprocessableInput,
+            nxt_unit::NXT_WEBSOCKET_CR_RESERVED => CloseCode::Reserved,
+            nxt_unit::NXT_WEBSOCKET_CR_NOT_PROVIDED => CloseCode::NotProvided,
+            nxt_unit::NXT_WEBSOCKET_CR_ABNORMAL => CloseCode::Abnormal,
+            nxt_unit::NXT_WEBSOCKET_CR_INVALID_DATA => CloseCode::InvalidData,
+            nxt_unit::NXT_WEBSOCKET_CR_POLICY_VIOLATION => CloseCode::PolicyViolation,
+            nxt_unit::NXT_WEBSOCKET_CR_MESSAGE_TOO_BIG => CloseCode::MessageTooBig,
+            nxt_unit::NXT_WEBSOCKET_CR_EXTENSION_REQUIRED => CloseCode::ExtensionRequired,
+            nxt_unit::NXT_WEBSOCKET_CR_INTERNAL_SERVER_ERROR => CloseCode::InternalServerError,
+            nxt_unit::NXT_WEBSOCKET_CR_TLS_HANDSHAKE_FAILED => CloseCode::TlsHandshakeFailed,
+            _ => CloseCode::Other(code),
+        }
+    }
+}
+
+/// Return the code with which to answer a close frame received from the peer.
+///
+/// The peer's code is echoed back, except for codes that RFC 6455 forbids
+/// sending in a close frame (1004, 1005, 1006, 1015 and other codes outside
+/// the 1000-4999 range, or in the unassigned 1016-2999 range), and for close
+/// frames without a code, which are answered with [`CloseCode::Normal`].
+fn close_reply_code(close_frame: Option<&CloseFrame>) -> CloseCode {
+    let code = match close_frame {
+        Some(close_frame) => close_frame.code,
+        None => return CloseCode::Normal,
+    };
+
+    match code {
+        CloseCode::Reserved
+        | CloseCode::NotProvided
+        | CloseCode::Abnormal
+        | CloseCode::TlsHandshakeFailed => CloseCode::Normal,
+        CloseCode::Other(code)
+            if !(1000..=1014).contains(&code) && !(3000..=4999).contains(&code) =>
+        {
+            CloseCode::Normal
+        }
+        code => code,
+    }
+}
+
+impl From<CloseCode> for u16 {
+    fn from(code: CloseCode) -> Self {
+        let code = match code {
+            CloseCode::Normal => nxt_unit::NXT_WEBSOCKET_CR_NORMAL,
+            CloseCode::GoingAway => nxt_unit::NXT_WEBSOCKET_CR_GOING_AWAY,
+            CloseCode::ProtocolError => nxt_unit::NXT_WEBSOCKET_CR_PROTOCOL_ERROR,
+            CloseCode::UnprocessableInput => nxt_unit::NXT_WEBSOCKET_CR_UNPROCESSABLE_INPUT,
+            CloseCode::Reserved => nxt_unit::NXT_WEBSOCKET_CR_RESERVED,
+            CloseCode::NotProvided => nxt_unit::NXT_WEBSOCKET_CR_NOT_PROVIDED,
+            CloseCode::Abnormal => nxt_unit::NXT_WEBSOCKET_CR_ABNORMAL,
+            CloseCode::InvalidData => nxt_unit::NXT_WEBSOCKET_CR_INVALID_DATA,
+            CloseCode::PolicyViolation => nxt_unit::NXT_WEBSOCKET_CR_POLICY_VIOLATION,
+            CloseCode::MessageTooBig => nxt_unit::NXT_WEBSOCKET_CR_MESSAGE_TOO_BIG,
+            CloseCode::ExtensionRequired => nxt_unit::NXT_WEBSOCKET_CR_EXTENSION_REQUIRED,
+            CloseCode::InternalServerError => nxt_unit::NXT_WEBSOCKET_CR_INTERNAL_SERVER_ERROR,
+            CloseCode::TlsHandshakeFailed => nxt_unit::NXT_WEBSOCKET_CR_TLS_HANDSHAKE_FAILED,
+            CloseCode::Other(code) => return code,
+        };
+        code as u16
+    }
+}
+
+impl WebSocket {
+    /// Send a complete text message in a single frame.
+    pub fn send_text(&self, text: impl AsRef<str>) -> UnitResult<()> {
+        self.send(Opcode::Text, true, text.as_ref())
+    }
+
+    /// Send a complete binary message in a single frame.
+    pub fn send_binary(&self, data: impl AsRef<[u8]>) -> UnitResult<()> {
+        self.send(Opcode::Binary, true, data)
+    }
+
+    /// Send a ping frame; the client will answer with a
+    /// [`WebSocketMessage::Pong`] containing the same payload.
+    pub fn send_ping(&self, payload: impl AsRef<[u8]>) -> UnitResult<()> {
+        self.send(Opcode::Ping, true, payload)
+    }
+
+    /// Start a close handshake by sending a close frame with the given code
+    /// and reason. The Unit server will close the connection once the client
+    /// answers.
+    pub fn close(&self, code: CloseCode, reason: impl AsRef<str>) -> UnitResult<()> {
+        let mut payload = u16::from(code).to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_ref().as_bytes());
+        self.send(Opcode::Close, true, payload)
+    }
+}
+
+/// A trait for WebSocket handlers that receive complete messages.
+///
+/// Handlers that implement this trait can be used with the [`MessageHandler`]
+/// adapter to convert them to a [`WebSocketHandler`].
+///
+/// This trait is automatically implemented for functions or lambda functions
+/// that take a [`&WebSocket`](WebSocket) and a [`WebSocketMessage`], and return
+/// a [`UnitResult<()>`](UnitResult).
+pub trait MessageService {
+    fn handle_message(&mut self, ws: &WebSocket, message: WebSocketMessage) -> UnitResult<()>;
+
+    /// Called once after the connection was closed, either by the client or by
+    /// the Unit server.
+    fn handle_close(&mut self, _ws: &WebSocket) {}
+}
+
+impl<F> MessageService for F
+where
+    F: FnMut(&WebSocket, WebSocketMessage) -> UnitResult<()> + 'static,
+{
+    fn handle_message(&mut self, ws: &WebSocket, message: WebSocketMessage) -> UnitResult<()> {
+        self(ws, message)
+    }
+}
+
+/// Adapter that reassembles WebSocket frames into complete messages.
+///
+/// The inner handler must implement the [`MessageService`] trait.
+///
+/// Fragments of an incomplete message are retained until the final fragment
+/// arrives. If a message grows beyond the maximum message size, or a text
+/// message is not valid UTF-8, the connection will be closed with the
+/// appropriate [`CloseCode`].
+pub struct MessageHandler<S: MessageService> {
+    service: S,
+    max_message_size: usize,
+    fragments: Vec<WebSocketFrame>,
+    fragments_size: u64,
+    close_sent: bool,
+}
+
+impl<S: MessageService> MessageHandler<S> {
+    pub fn new(service: S) -> Self {
+        MessageHandler {
+            service,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            fragments: Vec::new(),
+            fragments_size: 0,
+            close_sent: false,
+        }
+    }
+
+    /// Set the maximum size of a reassembled message, in bytes.
+    ///
+    /// Defaults to [`DEFAULT_MAX_MESSAGE_SIZE`].
+    pub fn max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    fn fail(&mut self, ws: &WebSocket, code: CloseCode, reason: &str) -> UnitResult<()> {
+        ws.log(LogLevel::Info, format!("Closing WebSocket: {}", reason));
+        self.fragments.clear();
+        self.fragments_size = 0;
+        self.send_close(ws, code, reason)
+    }
+
+    fn send_close(&mut self, ws: &WebSocket, code: CloseCode, reason: &str) -> UnitResult<()> {
+        if self.close_sent {
+            return Ok(());
+        }
+        self.close_sent = true;
+        ws.close(code, reason)
+    }
+
+    fn handle_control_frame(
+        &mut self,
+        ws: &WebSocket,
+        opcode: Opcode,
+        payload: Vec<u8>,
+    ) -> UnitResult<()> {
+        match opcode {
+            Opcode::Ping => ws.send(Opcode::Pong, true, payload),
+            Opcode::Pong => self
+                .service
+                .handle_message(ws, WebSocketMessage::Pong(payload)),
+            _ => {
+                let close_frame = match payload.len() {
+                    0 => None,
+                    1 => return self.fail(ws, CloseCode::ProtocolError, "Invalid close frame"),
+                    _ => {
+                        let code = u16::from_be_bytes([payload[0], payload[1]]);
+                        let reason = match String::from_utf8(payload[2..].to_vec()) {
+                            Ok(reason) => reason,
+                            Err(_) => {
+                                return self.fail(
+                                    ws,
+                                    CloseCode::InvalidData,
+                                    "Invalid close reason",
+                                )
+                            }
+                        };
+                        Some(CloseFrame {
+                            code: code.into(),
+                            reason,
+                        })
+                    }
+                };
+
+                self.send_close(ws, close_reply_code(close_frame.as_ref()), "")?;
+
+                self.service
+                    .handle_message(ws, WebSocketMessage::Close(close_frame))
+            }
+        }
+    }
+
+    fn assemble_message(&mut self, ws: &WebSocket) -> UnitResult<()> {
+        let mut payload = Vec::with_capacity(self.fragments_size as usize);
+        let mut fragments = std::mem::take(&mut self.fragments);
+        self.fragments_size = 0;
+
+        let opcode = fragments[0].opcode();
+
+        for fragment in &mut fragments {
+            if fragment.read_to_end(&mut payload).is_err() {
+                return Err(UnitError::error());
+            }
+        }
+        drop(fragments);
+
+        let message = if opcode == Opcode::Text {
+            match String::from_utf8(payload) {
+                Ok(text) => WebSocketMessage::Text(text),
+                Err(_) => {
+                    return self.fail(ws, CloseCode::InvalidData, "Invalid UTF-8 in text message")
+                }
+            }
+        } else {
+            WebSocketMessage::Binary(payload)
+        };
+
+        self.service.handle_message(ws, message)
+    }
+}
+
+impl<S: MessageService> WebSocketHandler for MessageHandler<S> {
+    fn handle_frame(&mut self, ws: &WebSocket, mut frame: WebSocketFrame) -> UnitResult<()> {
+        if self.close_sent && frame.opcode() != Opcode::Close {
+            // Everything but the close answer is ignored during a close
+            // handshake.
+            return Ok(());
+        }
+
+        let opcode = frame.opcode();
+
+        if opcode.is_control() {
+            if !frame.is_final() || frame.payload_len() > 125 {
+                return self.fail(ws, CloseCode::ProtocolError, "Invalid control frame");
+            }
+
+            let payload = frame.read_to_vec().map_err(|_| UnitError::error())?;
+            return self.handle_control_frame(ws, opcode, payload);
+        }
+
+        match (opcode, self.fragments.is_empty()) {
+            (Opcode::Text | Opcode::Binary, true) | (Opcode::Continuation, false) => {}
+            (Opcode::Text | Opcode::Binary, false) => {
+                return self.fail(ws, CloseCode::ProtocolError, "Expected continuation frame")
+            }
+            (Opcode::Continuation, true) => {
+                return self.fail(
+                    ws,
+                    CloseCode::ProtocolError,
+                    "Unexpected continuation frame",
+                )
+            }
+            _ => return self.fail(ws, CloseCode::ProtocolError, "Reserved opcode"),
+        }
+
+        self.fragments_size += frame.payload_len();
+
+        if self.fragments_size > self.max_message_size as u64 {
+            return self.fail(ws, CloseCode::MessageTooBig, "Message too big");
+        }
+
+        let is_final = frame.is_final();
+
+        // Incomplete fragments outlive this call, and are therefore retained
+        // outside of Unit's shared memory until the message is complete.
+        self.fragments.push(frame);
+
+        if is_final {
+            self.assemble_message(ws)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn handle_close(&mut self, ws: &WebSocket) {
+        self.fragments.clear();
+        self.fragments_size = 0;
+        self.service.handle_close(ws)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{close_reply_code, CloseCode, CloseFrame};
+
+    fn reply_to(code: u16) -> u16 {
+        let close_frame = CloseFrame {
+            code: code.into(),
+            reason: String::new(),
+        };
+        close_reply_code(Some(&close_frame)).into()
+    }
+
+    #[test]
+    fn close_reply_echoes_sendable_codes() {
+        assert_eq!(reply_to(1000), 1000);
+        assert_eq!(reply_to(1001), 1001);
+        assert_eq!(reply_to(1008), 1008);
+        assert_eq!(reply_to(1012), 1012);
+        assert_eq!(reply_to(3000), 3000);
+        assert_eq!(reply_to(4999), 4999);
+    }
+
+    #[test]
+    fn close_reply_replaces_reserved_codes() {
+        for code in [999, 1004, 1005, 1006, 1015, 1016, 2999, 5000] {
+            assert_eq!(reply_to(code), 1000, "code {}", code);
+        }
+    }
+
+    #[test]
+    fn close_reply_without_payload() {
+        assert_eq!(close_reply_code(None), CloseCode::Normal);
+    }
+}
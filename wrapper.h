@@ -1,3 +1,4 @@
 // Headers that `bindgen` will generate bindings for
 #include <nxt_unit.h>
 #include <nxt_unit_request.h>
+#include <nxt_unit_websocket.h>